@@ -0,0 +1,137 @@
+use std::cell::RefCell;
+
+// Everything the cpu sees of the rest of the machine. Mmu is the real one,
+// FlatBus stands in for it when testing the cpu on its own.
+pub trait Bus {
+    fn read(&self, address:u16) -> u8;
+    fn write(&mut self, address:u16, value:u8);
+    // lets the hardware behind the bus run for the cycles the cpu took
+    fn tick(&mut self, cycles:isize);
+    // the cpu is in STOP for the cycles, only the frame clock goes on
+    fn idle(&mut self, _cycles:isize) {
+    }
+    // IE and IF, the cpu checks them without a bus cycle
+    fn pending_interrupts(&self) -> u8 {
+        self.read(0xffff) & self.read(0xff0f) & 0x1f
+    }
+    // clears the IF bit of the interrupt being serviced
+    fn acknowledge_interrupt(&mut self, bit:u32) {
+        let flags = self.read(0xff0f);
+        self.write(0xff0f, flags & !(1 << bit));
+    }
+    // STOP was executed. Returns true if it switched the speed of a cgb,
+    // the cpu carries on then instead of stopping.
+    fn stop(&mut self) -> bool {
+        false
+    }
+}
+
+// 64k of plain ram without any hardware behind it
+pub struct FlatBus {
+    pub memory: Vec<u8>,
+}
+
+impl Default for FlatBus {
+    fn default() -> FlatBus {
+        FlatBus::new()
+    }
+}
+
+impl FlatBus {
+    pub fn new() -> FlatBus {
+        FlatBus {memory: vec![0; 0x10000]}
+    }
+}
+
+impl Bus for FlatBus {
+    fn read(&self, address:u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    fn write(&mut self, address:u16, value:u8) {
+        self.memory[address as usize] = value;
+    }
+
+    fn tick(&mut self, _cycles:isize) {
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read(u16, u8),
+    Write(u16, u8),
+}
+
+// Passes everything on to another bus and records the reads and writes
+pub struct LoggingBus<B:Bus> {
+    pub inner: B,
+    accesses: RefCell<Vec<Access>>,
+}
+
+impl<B:Bus> LoggingBus<B> {
+    pub fn new(inner:B) -> LoggingBus<B> {
+        LoggingBus {inner, accesses: RefCell::new(Vec::new())}
+    }
+
+    // the accesses since the last call, oldest first
+    pub fn take_accesses(&mut self) -> Vec<Access> {
+        self.accesses.take()
+    }
+}
+
+impl<B:Bus> Bus for LoggingBus<B> {
+    fn read(&self, address:u16) -> u8 {
+        let value = self.inner.read(address);
+        self.accesses.borrow_mut().push(Access::Read(address, value));
+        value
+    }
+
+    fn write(&mut self, address:u16, value:u8) {
+        self.inner.write(address, value);
+        self.accesses.borrow_mut().push(Access::Write(address, value));
+    }
+
+    fn tick(&mut self, cycles:isize) {
+        self.inner.tick(cycles);
+    }
+
+    fn idle(&mut self, cycles:isize) {
+        self.inner.idle(cycles);
+    }
+
+    // not bus accesses, so they are not logged
+    fn pending_interrupts(&self) -> u8 {
+        self.inner.pending_interrupts()
+    }
+
+    fn acknowledge_interrupt(&mut self, bit:u32) {
+        self.inner.acknowledge_interrupt(bit);
+    }
+
+    fn stop(&mut self) -> bool {
+        self.inner.stop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_logging_bus() {
+        let mut bus = LoggingBus::new(FlatBus::new());
+        bus.write(0xc000, 0x12);
+        assert_eq!(bus.read(0xc000), 0x12);
+        bus.tick(4);
+        assert_eq!(bus.take_accesses(), vec![Access::Write(0xc000, 0x12), Access::Read(0xc000, 0x12)]);
+        assert!(bus.take_accesses().is_empty());
+
+        // checking and acknowledging interrupts is no bus cycle
+        bus.inner.memory[0xffff] = 0x05;
+        bus.inner.memory[0xff0f] = 0x04;
+        assert_eq!(bus.pending_interrupts(), 0x04);
+        bus.acknowledge_interrupt(2);
+        assert_eq!(bus.pending_interrupts(), 0);
+        assert!(bus.take_accesses().is_empty());
+    }
+}
@@ -0,0 +1,1221 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cpu::word;
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub const ROM_BANK_SIZE:usize = 0x4000;
+pub const RAM_BANK_SIZE:usize = 0x2000;
+
+pub trait Mbc {
+    // 0x0000..=0x7fff
+    fn read_rom(&self, address:u16) -> u8;
+    // 0xa000..=0xbfff
+    fn read_ram(&self, address:u16) -> u8;
+    // writes to the rom area go to the mapper registers
+    fn write_rom(&mut self, address:u16, value:u8);
+    fn write_ram(&mut self, address:u16, value:u8);
+    // called with the new motor state whenever a rumble cartridge switches its motor
+    fn set_rumble_callback(&mut self, _callback:Box<dyn FnMut(bool)>) {
+    }
+    // battery backed ram as stored in .sav files
+    fn save_data(&self) -> Vec<u8> {
+        Vec::new()
+    }
+    fn load_save_data(&mut self, _data:&[u8]) {
+    }
+    // true if the save data changed since the last call
+    fn take_dirty(&mut self) -> bool {
+        false
+    }
+    // mapper registers and ram for save states
+    fn save_state(&self, state:&mut StateWriter);
+    fn load_state(&mut self, state:&mut StateReader) -> Result<(), StateError>;
+}
+
+// save states start the cartridge section with the mapper number so they
+// can not be loaded into a different kind of cartridge
+fn check_mapper(state:&mut StateReader, mapper:u8) -> Result<(), StateError> {
+    if state.u8()? != mapper {
+        return Err(StateError::WrongCartridge);
+    }
+    Ok(())
+}
+
+fn load_ram(state:&mut StateReader, ram:&mut [u8]) -> Result<(), StateError> {
+    let data = state.bytes()?;
+    if data.len() != ram.len() {
+        return Err(StateError::WrongCartridge);
+    }
+    ram.copy_from_slice(data);
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(io::Error),
+    TooSmall(usize),
+    UnsupportedRomSize(u8),
+    UnsupportedRamSize(u8),
+    UnsupportedType(u8),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        use CartridgeError::*;
+        match self {
+            Io(err) => write!(f, "could not read rom: {}", err),
+            TooSmall(len) => write!(f, "rom is too small to hold a cartridge header ({} bytes)", len),
+            UnsupportedRomSize(code) => write!(f, "unknown rom size code 0x{:02x}", code),
+            UnsupportedRamSize(code) => write!(f, "unknown ram size code 0x{:02x}", code),
+            UnsupportedType(code) => write!(f, "unsupported cartridge type 0x{:02x} ({})", code, type_name(*code)),
+        }
+    }
+}
+
+impl error::Error for CartridgeError {}
+
+impl From<io::Error> for CartridgeError {
+    fn from(err:io::Error) -> CartridgeError {
+        CartridgeError::Io(err)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum MapperType {
+    RomOnly,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+}
+
+// the cartridge header at 0x0100..=0x014f
+#[derive(Clone, PartialEq, Debug)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub manufacturer: String,
+    pub cgb_flag: u8,
+    pub sgb_flag: u8,
+    pub cartridge_type: u8,
+    pub rom_size_code: u8,
+    pub ram_size_code: u8,
+    pub destination: u8,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+    pub header_checksum_ok: bool,
+    pub global_checksum_ok: bool,
+    // bytes in the rom file, overdumped and trimmed files differ from rom_size
+    pub file_size: usize,
+}
+
+fn type_name(cartridge_type:u8) -> &'static str {
+    match cartridge_type {
+        0x00 => "ROM ONLY",
+        0x01 => "MBC1",
+        0x02 => "MBC1+RAM",
+        0x03 => "MBC1+RAM+BATTERY",
+        0x05 => "MBC2",
+        0x06 => "MBC2+BATTERY",
+        0x08 => "ROM+RAM",
+        0x09 => "ROM+RAM+BATTERY",
+        0x0b => "MMM01",
+        0x0c => "MMM01+RAM",
+        0x0d => "MMM01+RAM+BATTERY",
+        0x0f => "MBC3+TIMER+BATTERY",
+        0x10 => "MBC3+TIMER+RAM+BATTERY",
+        0x11 => "MBC3",
+        0x12 => "MBC3+RAM",
+        0x13 => "MBC3+RAM+BATTERY",
+        0x19 => "MBC5",
+        0x1a => "MBC5+RAM",
+        0x1b => "MBC5+RAM+BATTERY",
+        0x1c => "MBC5+RUMBLE",
+        0x1d => "MBC5+RUMBLE+RAM",
+        0x1e => "MBC5+RUMBLE+RAM+BATTERY",
+        0x20 => "MBC6",
+        0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+        0xfc => "POCKET CAMERA",
+        0xfd => "BANDAI TAMA5",
+        0xfe => "HuC3",
+        0xff => "HuC1+RAM+BATTERY",
+        _ => "unknown",
+    }
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom:&[u8]) -> Result<CartridgeHeader, CartridgeError> {
+        if rom.len() < 0x150 {
+            return Err(CartridgeError::TooSmall(rom.len()));
+        }
+
+        let text = |bytes:&[u8]| -> String {
+            bytes.iter().take_while(|&&b| b != 0).map(|&b| b as char).collect()
+        };
+        let cgb_flag = rom[0x143];
+        // cgb cartridges use the end of the title area for the cgb flag and
+        // some also for a manufacturer code, on dmg ones it is all title
+        let cgb = cgb_flag & 0x80 != 0;
+        let manufacturer_bytes = &rom[0x13f..0x143];
+        let manufacturer = if cgb && manufacturer_bytes.iter().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit()) {
+            text(manufacturer_bytes)
+        } else {
+            String::new()
+        };
+        let title_end = if !manufacturer.is_empty() {0x13f} else if cgb {0x143} else {0x144};
+
+        let mut checksum:u8 = 0;
+        for &b in &rom[0x134..=0x14c] {
+            checksum = checksum.wrapping_sub(b).wrapping_sub(1);
+        }
+        let mut global:u16 = 0;
+        for (i, &b) in rom.iter().enumerate() {
+            if i != 0x14e && i != 0x14f {
+                global = global.wrapping_add(b as u16);
+            }
+        }
+
+        let header = CartridgeHeader {
+            title: text(&rom[0x134..title_end]).trim_end().to_string(),
+            manufacturer,
+            cgb_flag,
+            sgb_flag: rom[0x146],
+            cartridge_type: rom[0x147],
+            rom_size_code: rom[0x148],
+            ram_size_code: rom[0x149],
+            destination: rom[0x14a],
+            version: rom[0x14c],
+            header_checksum: rom[0x14d],
+            global_checksum: word(rom[0x14e], rom[0x14f]),
+            header_checksum_ok: checksum == rom[0x14d],
+            global_checksum_ok: global == word(rom[0x14e], rom[0x14f]),
+            file_size: rom.len(),
+        };
+        header.rom_size()?;
+        header.ram_size()?;
+        Ok(header)
+    }
+
+    pub fn rom_size(&self) -> Result<usize, CartridgeError> {
+        match self.rom_size_code {
+            0x00..=0x08 => Ok(0x8000 << self.rom_size_code),
+            code => Err(CartridgeError::UnsupportedRomSize(code)),
+        }
+    }
+
+    pub fn ram_size(&self) -> Result<usize, CartridgeError> {
+        match self.ram_size_code {
+            0 => Ok(0),
+            1 => Ok(0x800),
+            2 => Ok(0x2000),
+            3 => Ok(0x8000),
+            4 => Ok(0x20000),
+            5 => Ok(0x10000),
+            code => Err(CartridgeError::UnsupportedRamSize(code)),
+        }
+    }
+
+    pub fn mapper(&self) -> Result<MapperType, CartridgeError> {
+        match self.cartridge_type {
+            0x00 | 0x08 | 0x09 => Ok(MapperType::RomOnly),
+            0x01..=0x03 => Ok(MapperType::Mbc1),
+            0x05 | 0x06 => Ok(MapperType::Mbc2),
+            0x0f..=0x13 => Ok(MapperType::Mbc3),
+            0x19..=0x1e => Ok(MapperType::Mbc5),
+            code => Err(CartridgeError::UnsupportedType(code)),
+        }
+    }
+
+    // for the frontend to show when the file does not match the header
+    pub fn size_warning(&self) -> Option<String> {
+        let rom_size = self.rom_size().ok()?;
+        if self.file_size == rom_size {
+            return None;
+        }
+        Some(format!("header declares {} bytes of rom, file has {} bytes", rom_size, self.file_size))
+    }
+
+    pub fn has_battery(&self) -> bool {
+        matches!(self.cartridge_type, 0x03 | 0x06 | 0x09 | 0x0d | 0x0f | 0x10 | 0x13 | 0x1b | 0x1e | 0x22 | 0xff)
+    }
+
+    pub fn has_rtc(&self) -> bool {
+        matches!(self.cartridge_type, 0x0f | 0x10)
+    }
+
+    pub fn has_rumble(&self) -> bool {
+        matches!(self.cartridge_type, 0x1c..=0x1e | 0x22)
+    }
+}
+
+impl fmt::Display for CartridgeHeader {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        let size = |size:Result<usize, CartridgeError>| match size {
+            Ok(bytes) => format!("{} KiB", bytes / 1024),
+            Err(_) => "unknown".to_string(),
+        };
+        writeln!(f, "title:           {}", self.title)?;
+        writeln!(f, "manufacturer:    {}", self.manufacturer)?;
+        writeln!(f, "cgb flag:        0x{:02x}{}", self.cgb_flag, match self.cgb_flag {
+            0x80 => " (cgb enhanced)",
+            0xc0 => " (cgb only)",
+            _ => "",
+        })?;
+        writeln!(f, "sgb flag:        0x{:02x}{}", self.sgb_flag, if self.sgb_flag == 0x03 {" (sgb functions)"} else {""})?;
+        writeln!(f, "cartridge type:  0x{:02x} ({})", self.cartridge_type, type_name(self.cartridge_type))?;
+        writeln!(f, "rom size:        0x{:02x} ({})", self.rom_size_code, size(self.rom_size()))?;
+        writeln!(f, "ram size:        0x{:02x} ({})", self.ram_size_code, size(self.ram_size()))?;
+        writeln!(f, "destination:     0x{:02x} ({})", self.destination, if self.destination == 0 {"japan"} else {"overseas"})?;
+        writeln!(f, "version:         {}", self.version)?;
+        writeln!(f, "header checksum: 0x{:02x} ({})", self.header_checksum, if self.header_checksum_ok {"ok"} else {"bad"})?;
+        write!(f, "global checksum: 0x{:04x} ({})", self.global_checksum, if self.global_checksum_ok {"ok"} else {"bad"})
+    }
+}
+
+pub fn create(header:&CartridgeHeader, mut rom:Vec<u8>) -> Result<Box<dyn Mbc>, CartridgeError> {
+    // trimmed roms are padded to the size of the header, longer ones keep
+    // their extra banks in case the header under-reports the size
+    let rom_size = header.rom_size()?.max(rom.len().div_ceil(ROM_BANK_SIZE) * ROM_BANK_SIZE);
+    rom.resize(rom_size, 0xff);
+    let ram_size = header.ram_size()?;
+    Ok(match header.mapper()? {
+        MapperType::RomOnly => Box::new(RomOnly::new(rom, ram_size)),
+        MapperType::Mbc1 => Box::new(Mbc1::new(rom, ram_size)),
+        MapperType::Mbc2 => Box::new(Mbc2::new(rom)),
+        MapperType::Mbc3 => {
+            let rtc = if header.has_rtc() {Some(Rtc::new(Box::new(SystemClock)))} else {None};
+            Box::new(Mbc3::new(rom, ram_size, rtc))
+        },
+        MapperType::Mbc5 => Box::new(Mbc5::new(rom, ram_size, header.has_rumble())),
+    })
+}
+
+// bank numbers wrap around at the rom size like the unconnected upper address lines do
+fn rom_byte(rom:&[u8], bank:usize, address:u16) -> u8 {
+    let banks = (rom.len() / ROM_BANK_SIZE).max(1);
+    let offset = (bank % banks) * ROM_BANK_SIZE + (address as usize & 0x3fff);
+    *rom.get(offset).unwrap_or(&0xff)
+}
+
+fn copy_save_data(ram:&mut [u8], data:&[u8]) {
+    let len = ram.len().min(data.len());
+    ram[..len].copy_from_slice(&data[..len]);
+}
+
+pub struct RomOnly {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    dirty: bool,
+}
+
+impl RomOnly {
+    pub fn new(rom:Vec<u8>, ram_size:usize) -> RomOnly {
+        RomOnly {rom, ram: vec![0; ram_size], dirty: false}
+    }
+}
+
+impl Mbc for RomOnly {
+    fn read_rom(&self, address:u16) -> u8 {
+        *self.rom.get(address as usize).unwrap_or(&0xff)
+    }
+
+    fn read_ram(&self, address:u16) -> u8 {
+        *self.ram.get((address & 0x1fff) as usize).unwrap_or(&0xff)
+    }
+
+    fn write_rom(&mut self, _address:u16, _value:u8) {
+    }
+
+    fn write_ram(&mut self, address:u16, value:u8) {
+        if let Some(byte) = self.ram.get_mut((address & 0x1fff) as usize) {
+            *byte = value;
+            self.dirty = true;
+        }
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        self.ram.to_vec()
+    }
+
+    fn load_save_data(&mut self, data:&[u8]) {
+        copy_save_data(&mut self.ram, data);
+    }
+
+    fn take_dirty(&mut self) -> bool {
+        let dirty = self.dirty;
+        self.dirty = false;
+        dirty
+    }
+
+    fn save_state(&self, state:&mut StateWriter) {
+        state.u8(0);
+        state.bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, state:&mut StateReader) -> Result<(), StateError> {
+        check_mapper(state, 0)?;
+        load_ram(state, &mut self.ram)
+    }
+}
+
+pub struct Mbc1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enable: bool,
+    // 5 bit register at 0x2000..=0x3fff, never 0
+    bank1: u8,
+    // 2 bit register at 0x4000..=0x5fff, upper rom bank bits or ram bank
+    bank2: u8,
+    // mode 1 applies bank2 to the 0x0000..=0x3fff and ram areas as well
+    mode: u8,
+    dirty: bool,
+}
+
+impl Mbc1 {
+    pub fn new(rom:Vec<u8>, ram_size:usize) -> Mbc1 {
+        Mbc1 {
+            rom,
+            ram: vec![0; ram_size],
+            ram_enable: false,
+            bank1: 1,
+            bank2: 0,
+            mode: 0,
+            dirty: false,
+        }
+    }
+
+    fn ram_offset(&self, address:u16) -> Option<usize> {
+        if !self.ram_enable || self.ram.is_empty() {
+            return None;
+        }
+        let bank = if self.mode == 1 {self.bank2 as usize} else {0};
+        Some((bank * RAM_BANK_SIZE + (address as usize & 0x1fff)) % self.ram.len())
+    }
+}
+
+impl Mbc for Mbc1 {
+    fn read_rom(&self, address:u16) -> u8 {
+        match address {
+            0x0000..=0x3fff => {
+                let bank = if self.mode == 1 {(self.bank2 as usize) << 5} else {0};
+                rom_byte(&self.rom, bank, address)
+            },
+            _ => rom_byte(&self.rom, (self.bank2 as usize) << 5 | self.bank1 as usize, address),
+        }
+    }
+
+    fn read_ram(&self, address:u16) -> u8 {
+        match self.ram_offset(address) {
+            Some(offset) => self.ram[offset],
+            None => 0xff,
+        }
+    }
+
+    fn write_rom(&mut self, address:u16, value:u8) {
+        match address {
+            0x0000..=0x1fff => {self.ram_enable = value & 0x0f == 0x0a;},
+            0x2000..=0x3fff => {
+                // bank 0 can not be selected, this also turns 0x20, 0x40 and 0x60 into 0x21, 0x41 and 0x61
+                self.bank1 = value & 0x1f;
+                if self.bank1 == 0 {
+                    self.bank1 = 1;
+                }
+            },
+            0x4000..=0x5fff => {self.bank2 = value & 0x03;},
+            _ => {self.mode = value & 0x01;},
+        }
+    }
+
+    fn write_ram(&mut self, address:u16, value:u8) {
+        if let Some(offset) = self.ram_offset(address) {
+            self.ram[offset] = value;
+            self.dirty = true;
+        }
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        self.ram.to_vec()
+    }
+
+    fn load_save_data(&mut self, data:&[u8]) {
+        copy_save_data(&mut self.ram, data);
+    }
+
+    fn take_dirty(&mut self) -> bool {
+        let dirty = self.dirty;
+        self.dirty = false;
+        dirty
+    }
+
+    fn save_state(&self, state:&mut StateWriter) {
+        state.u8(1);
+        state.bytes(&self.ram);
+        state.bool(self.ram_enable);
+        state.u8(self.bank1);
+        state.u8(self.bank2);
+        state.u8(self.mode);
+    }
+
+    fn load_state(&mut self, state:&mut StateReader) -> Result<(), StateError> {
+        check_mapper(state, 1)?;
+        load_ram(state, &mut self.ram)?;
+        self.ram_enable = state.bool()?;
+        self.bank1 = state.u8()?;
+        self.bank2 = state.u8()?;
+        self.mode = state.u8()?;
+        Ok(())
+    }
+}
+
+pub struct Mbc2 {
+    rom: Vec<u8>,
+    // 512 half bytes built into the mapper, only the low nibble is stored
+    ram: [u8;0x200],
+    ram_enable: bool,
+    rom_bank: u8,
+    dirty: bool,
+}
+
+impl Mbc2 {
+    pub fn new(rom:Vec<u8>) -> Mbc2 {
+        Mbc2 {
+            rom,
+            ram: [0;0x200],
+            ram_enable: false,
+            rom_bank: 1,
+            dirty: false,
+        }
+    }
+}
+
+impl Mbc for Mbc2 {
+    fn read_rom(&self, address:u16) -> u8 {
+        match address {
+            0x0000..=0x3fff => rom_byte(&self.rom, 0, address),
+            _ => rom_byte(&self.rom, self.rom_bank as usize, address),
+        }
+    }
+
+    fn read_ram(&self, address:u16) -> u8 {
+        if !self.ram_enable {
+            return 0xff;
+        }
+        // the upper nibble is not connected and reads as 1s
+        0xf0 | self.ram[(address & 0x1ff) as usize]
+    }
+
+    fn write_rom(&mut self, address:u16, value:u8) {
+        // address bit 8 selects between the two registers, both live in 0x0000..=0x3fff
+        if address >= 0x4000 {
+            return;
+        }
+        if address & 0x100 == 0 {
+            self.ram_enable = value & 0x0f == 0x0a;
+        } else {
+            self.rom_bank = value & 0x0f;
+            if self.rom_bank == 0 {
+                self.rom_bank = 1;
+            }
+        }
+    }
+
+    fn write_ram(&mut self, address:u16, value:u8) {
+        if self.ram_enable {
+            self.ram[(address & 0x1ff) as usize] = value & 0x0f;
+            self.dirty = true;
+        }
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        self.ram.to_vec()
+    }
+
+    fn load_save_data(&mut self, data:&[u8]) {
+        copy_save_data(&mut self.ram, data);
+        for nibble in self.ram.iter_mut() {
+            *nibble &= 0x0f;
+        }
+    }
+
+    fn take_dirty(&mut self) -> bool {
+        let dirty = self.dirty;
+        self.dirty = false;
+        dirty
+    }
+
+    fn save_state(&self, state:&mut StateWriter) {
+        state.u8(2);
+        state.bytes(&self.ram);
+        state.bool(self.ram_enable);
+        state.u8(self.rom_bank);
+    }
+
+    fn load_state(&mut self, state:&mut StateReader) -> Result<(), StateError> {
+        check_mapper(state, 2)?;
+        load_ram(state, &mut self.ram)?;
+        self.ram_enable = state.bool()?;
+        self.rom_bank = state.u8()?;
+        Ok(())
+    }
+}
+
+// time source for the MBC3 real time clock, in seconds
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+    }
+}
+
+// 5 current and 5 latched registers as 32 bit words followed by a 64 bit unix timestamp,
+// the footer most emulators append to the ram of MBC3 timer cartridges
+const RTC_FOOTER_SIZE:usize = 48;
+
+mod rtc_flags {
+    pub const DAY_HIGH:u8 = 0x01;
+    pub const HALT:u8 = 0x40;
+    pub const DAY_CARRY:u8 = 0x80;
+}
+
+pub struct Rtc {
+    clock: Box<dyn Clock>,
+    // seconds, minutes, hours, day low, day high / flags as in registers 0x08..=0x0c
+    regs: [u8;5],
+    latched: [u8;5],
+    // host time the registers were last brought up to date
+    base: u64,
+    latch_prev: u8,
+}
+
+impl Rtc {
+    pub fn new(clock:Box<dyn Clock>) -> Rtc {
+        let base = clock.now();
+        Rtc {clock, regs: [0;5], latched: [0;5], base, latch_prev: 0xff}
+    }
+
+    fn update(&mut self) {
+        let now = self.clock.now();
+        let elapsed = now.saturating_sub(self.base);
+        self.base = now;
+        if elapsed == 0 || self.regs[4] & rtc_flags::HALT != 0 {
+            return;
+        }
+
+        let seconds = self.regs[0] as u64 + elapsed;
+        self.regs[0] = (seconds % 60) as u8;
+        let minutes = self.regs[1] as u64 + seconds / 60;
+        self.regs[1] = (minutes % 60) as u8;
+        let hours = self.regs[2] as u64 + minutes / 60;
+        self.regs[2] = (hours % 24) as u8;
+        let days = word(self.regs[4] & rtc_flags::DAY_HIGH, self.regs[3]) as u64 + hours / 24;
+        if days > 0x1ff {
+            self.regs[4] |= rtc_flags::DAY_CARRY;
+        }
+        self.regs[3] = (days & 0xff) as u8;
+        self.regs[4] = (self.regs[4] & !rtc_flags::DAY_HIGH) | ((days >> 8) & 1) as u8;
+    }
+
+    fn latch(&mut self, value:u8) {
+        if self.latch_prev == 0 && value == 1 {
+            self.update();
+            self.latched = self.regs;
+        }
+        self.latch_prev = value;
+    }
+
+    fn read(&self, reg:u8) -> u8 {
+        match reg {
+            0x08 => self.latched[0] & 0x3f,
+            0x09 => self.latched[1] & 0x3f,
+            0x0a => self.latched[2] & 0x1f,
+            0x0b => self.latched[3],
+            0x0c => self.latched[4] & 0xc1,
+            _ => 0xff,
+        }
+    }
+
+    fn footer(&self) -> Vec<u8> {
+        let mut footer = Vec::with_capacity(RTC_FOOTER_SIZE);
+        for &reg in self.regs.iter().chain(self.latched.iter()) {
+            footer.extend_from_slice(&(reg as u32).to_le_bytes());
+        }
+        footer.extend_from_slice(&self.base.to_le_bytes());
+        footer
+    }
+
+    fn load_footer(&mut self, footer:&[u8]) {
+        let le32 = |i:usize| u32::from_le_bytes([footer[i], footer[i+1], footer[i+2], footer[i+3]]);
+        for i in 0..5 {
+            self.regs[i] = le32(4 * i) as u8;
+            self.latched[i] = le32(20 + 4 * i) as u8;
+        }
+        // some emulators only store a 32 bit timestamp
+        self.base = if footer.len() >= RTC_FOOTER_SIZE {
+            u64::from_le_bytes([footer[40], footer[41], footer[42], footer[43], footer[44], footer[45], footer[46], footer[47]])
+        } else {
+            le32(40) as u64
+        };
+        self.update();
+    }
+
+    fn save_state(&self, state:&mut StateWriter) {
+        state.bytes(&self.regs);
+        state.bytes(&self.latched);
+        state.u64(self.base);
+        state.u8(self.latch_prev);
+    }
+
+    fn load_state(&mut self, state:&mut StateReader) -> Result<(), StateError> {
+        state.bytes_into(&mut self.regs)?;
+        state.bytes_into(&mut self.latched)?;
+        self.base = state.u64()?;
+        self.latch_prev = state.u8()?;
+        Ok(())
+    }
+
+    fn write(&mut self, reg:u8, value:u8) {
+        self.update();
+        match reg {
+            0x08 => {self.regs[0] = value & 0x3f;},
+            0x09 => {self.regs[1] = value & 0x3f;},
+            0x0a => {self.regs[2] = value & 0x1f;},
+            0x0b => {self.regs[3] = value;},
+            0x0c => {self.regs[4] = value & 0xc1;},
+            _ => (),
+        }
+    }
+}
+
+pub struct Mbc3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rtc: Option<Rtc>,
+    ram_enable: bool,
+    rom_bank: u8,
+    // 0x00..=0x03 select a ram bank, 0x08..=0x0c a clock register
+    ram_select: u8,
+    dirty: bool,
+}
+
+impl Mbc3 {
+    pub fn new(rom:Vec<u8>, ram_size:usize, rtc:Option<Rtc>) -> Mbc3 {
+        Mbc3 {
+            rom,
+            ram: vec![0; ram_size],
+            rtc,
+            ram_enable: false,
+            rom_bank: 1,
+            ram_select: 0,
+            dirty: false,
+        }
+    }
+
+    fn ram_offset(&self, address:u16) -> Option<usize> {
+        if self.ram.is_empty() {
+            return None;
+        }
+        let offset = self.ram_select as usize * RAM_BANK_SIZE + (address as usize & 0x1fff);
+        Some(offset % self.ram.len())
+    }
+}
+
+impl Mbc for Mbc3 {
+    fn read_rom(&self, address:u16) -> u8 {
+        match address {
+            0x0000..=0x3fff => rom_byte(&self.rom, 0, address),
+            _ => rom_byte(&self.rom, self.rom_bank as usize, address),
+        }
+    }
+
+    fn read_ram(&self, address:u16) -> u8 {
+        if !self.ram_enable {
+            return 0xff;
+        }
+        match (self.ram_select, &self.rtc) {
+            (0x00..=0x03, _) => match self.ram_offset(address) {
+                Some(offset) => self.ram[offset],
+                None => 0xff,
+            },
+            (0x08..=0x0c, Some(rtc)) => rtc.read(self.ram_select),
+            _ => 0xff,
+        }
+    }
+
+    fn write_rom(&mut self, address:u16, value:u8) {
+        match address {
+            0x0000..=0x1fff => {self.ram_enable = value & 0x0f == 0x0a;},
+            0x2000..=0x3fff => {
+                self.rom_bank = value & 0x7f;
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            },
+            0x4000..=0x5fff => {self.ram_select = value & 0x0f;},
+            _ => if let Some(rtc) = &mut self.rtc {
+                rtc.latch(value);
+            },
+        }
+    }
+
+    fn write_ram(&mut self, address:u16, value:u8) {
+        if !self.ram_enable {
+            return;
+        }
+        match self.ram_select {
+            0x00..=0x03 => if let Some(offset) = self.ram_offset(address) {
+                self.ram[offset] = value;
+                self.dirty = true;
+            },
+            0x08..=0x0c => if let Some(rtc) = &mut self.rtc {
+                rtc.write(self.ram_select, value);
+                self.dirty = true;
+            },
+            _ => (),
+        }
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        let mut data = self.ram.to_vec();
+        if let Some(rtc) = &self.rtc {
+            data.extend(rtc.footer());
+        }
+        data
+    }
+
+    fn load_save_data(&mut self, data:&[u8]) {
+        copy_save_data(&mut self.ram, data);
+        if let Some(rtc) = &mut self.rtc {
+            if data.len() >= self.ram.len() + RTC_FOOTER_SIZE - 4 {
+                rtc.load_footer(&data[self.ram.len()..]);
+            }
+        }
+    }
+
+    fn take_dirty(&mut self) -> bool {
+        let dirty = self.dirty;
+        self.dirty = false;
+        dirty
+    }
+
+    fn save_state(&self, state:&mut StateWriter) {
+        state.u8(3);
+        state.bytes(&self.ram);
+        state.bool(self.ram_enable);
+        state.u8(self.rom_bank);
+        state.u8(self.ram_select);
+        state.bool(self.rtc.is_some());
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(state);
+        }
+    }
+
+    fn load_state(&mut self, state:&mut StateReader) -> Result<(), StateError> {
+        check_mapper(state, 3)?;
+        load_ram(state, &mut self.ram)?;
+        self.ram_enable = state.bool()?;
+        self.rom_bank = state.u8()?;
+        self.ram_select = state.u8()?;
+        if state.bool()? != self.rtc.is_some() {
+            return Err(StateError::WrongCartridge);
+        }
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load_state(state)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Mbc5 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enable: bool,
+    // 9 bit, bank 0 can be mapped to 0x4000..=0x7fff
+    rom_bank: u16,
+    ram_bank: u8,
+    // on rumble cartridges bit 3 of the ram bank register drives the motor
+    rumble: bool,
+    motor: bool,
+    rumble_callback: Option<Box<dyn FnMut(bool)>>,
+    dirty: bool,
+}
+
+impl Mbc5 {
+    pub fn new(rom:Vec<u8>, ram_size:usize, rumble:bool) -> Mbc5 {
+        Mbc5 {
+            rom,
+            ram: vec![0; ram_size],
+            ram_enable: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            rumble,
+            motor: false,
+            rumble_callback: None,
+            dirty: false,
+        }
+    }
+
+    fn ram_offset(&self, address:u16) -> Option<usize> {
+        if !self.ram_enable || self.ram.is_empty() {
+            return None;
+        }
+        let offset = self.ram_bank as usize * RAM_BANK_SIZE + (address as usize & 0x1fff);
+        Some(offset % self.ram.len())
+    }
+}
+
+impl Mbc for Mbc5 {
+    fn read_rom(&self, address:u16) -> u8 {
+        match address {
+            0x0000..=0x3fff => rom_byte(&self.rom, 0, address),
+            _ => rom_byte(&self.rom, self.rom_bank as usize, address),
+        }
+    }
+
+    fn read_ram(&self, address:u16) -> u8 {
+        match self.ram_offset(address) {
+            Some(offset) => self.ram[offset],
+            None => 0xff,
+        }
+    }
+
+    fn write_rom(&mut self, address:u16, value:u8) {
+        match address {
+            0x0000..=0x1fff => {self.ram_enable = value & 0x0f == 0x0a;},
+            0x2000..=0x2fff => {self.rom_bank = (self.rom_bank & 0x100) | value as u16;},
+            0x3000..=0x3fff => {self.rom_bank = (self.rom_bank & 0xff) | ((value as u16 & 1) << 8);},
+            0x4000..=0x5fff => if self.rumble {
+                self.ram_bank = value & 0x07;
+                let motor = value & 0x08 != 0;
+                if motor != self.motor {
+                    self.motor = motor;
+                    if let Some(callback) = &mut self.rumble_callback {
+                        callback(motor);
+                    }
+                }
+            } else {
+                self.ram_bank = value & 0x0f;
+            },
+            _ => (),
+        }
+    }
+
+    fn write_ram(&mut self, address:u16, value:u8) {
+        if let Some(offset) = self.ram_offset(address) {
+            self.ram[offset] = value;
+            self.dirty = true;
+        }
+    }
+
+    fn set_rumble_callback(&mut self, callback:Box<dyn FnMut(bool)>) {
+        if self.rumble {
+            self.rumble_callback = Some(callback);
+        }
+    }
+    fn save_data(&self) -> Vec<u8> {
+        self.ram.to_vec()
+    }
+
+    fn load_save_data(&mut self, data:&[u8]) {
+        copy_save_data(&mut self.ram, data);
+    }
+
+    fn take_dirty(&mut self) -> bool {
+        let dirty = self.dirty;
+        self.dirty = false;
+        dirty
+    }
+
+    fn save_state(&self, state:&mut StateWriter) {
+        state.u8(5);
+        state.bytes(&self.ram);
+        state.bool(self.ram_enable);
+        state.u16(self.rom_bank);
+        state.u8(self.ram_bank);
+        state.bool(self.motor);
+    }
+
+    fn load_state(&mut self, state:&mut StateReader) -> Result<(), StateError> {
+        check_mapper(state, 5)?;
+        load_ram(state, &mut self.ram)?;
+        self.ram_enable = state.bool()?;
+        self.rom_bank = state.u16()?;
+        self.ram_bank = state.u8()?;
+        let motor = state.bool()?;
+        if motor != self.motor {
+            self.motor = motor;
+            if let Some(callback) = &mut self.rumble_callback {
+                callback(motor);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::cell::Cell;
+
+    struct FakeClock(Rc<Cell<u64>>);
+
+    impl Clock for FakeClock {
+        fn now(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    #[test]
+    fn test_header() {
+        let mut rom = numbered_rom(4);
+        rom[0x134..0x13d].copy_from_slice(b"TEST GAME");
+        rom[0x147] = 0x13;
+        rom[0x148] = 0x01;
+        rom[0x149] = 0x03;
+        rom[0x14a] = 0x01;
+        rom[0x14d] = 0x55;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "TEST GAME");
+        assert_eq!(header.manufacturer, "");
+        assert_eq!(header.mapper().unwrap(), MapperType::Mbc3);
+        assert!(header.has_battery());
+        assert!(!header.has_rtc());
+        assert_eq!(header.rom_size().unwrap(), 0x10000);
+        assert_eq!(header.ram_size().unwrap(), 0x8000);
+        assert!(header.header_checksum_ok);
+        assert!(!header.global_checksum_ok);
+        assert!(create(&header, rom.clone()).is_ok());
+
+        assert_eq!(header.size_warning(), None);
+
+        // trimmed and overdumped
+        let trimmed = &rom[..0xc000];
+        let header = CartridgeHeader::parse(trimmed).unwrap();
+        assert!(header.size_warning().is_some());
+        let mut mbc = create(&header, trimmed.to_vec()).unwrap();
+        mbc.write_rom(0x2000, 3);
+        assert_eq!(mbc.read_rom(0x4000), 0xff);
+        let mut overdumped = numbered_rom(8);
+        overdumped[0x100..0x150].copy_from_slice(&rom[0x100..0x150]);
+        overdumped.extend_from_slice(&[0x09; 0x100]);
+        let header = CartridgeHeader::parse(&overdumped).unwrap();
+        assert_eq!(header.size_warning().unwrap(), "header declares 65536 bytes of rom, file has 131328 bytes");
+        // the banks past the header size stay, the partial one is padded
+        let mut mbc = create(&header, overdumped).unwrap();
+        mbc.write_rom(0x2000, 5);
+        assert_eq!(mbc.read_rom(0x4000), 5);
+        mbc.write_rom(0x2000, 8);
+        assert_eq!(mbc.read_rom(0x4000), 0x09);
+        assert_eq!(mbc.read_rom(0x4100), 0xff);
+
+        rom[0x147] = 0xfe;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert!(matches!(create(&header, rom.clone()), Err(CartridgeError::UnsupportedType(0xfe))));
+        assert!(matches!(CartridgeHeader::parse(&rom[..0x100]), Err(CartridgeError::TooSmall(0x100))));
+    }
+
+    #[test]
+    fn test_long_title() {
+        // a dmg title may run up to 0x143, it has no manufacturer code
+        let mut rom = numbered_rom(2);
+        rom[0x134..0x143].copy_from_slice(b"SUPER MARIOLAND");
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "SUPER MARIOLAND");
+        assert_eq!(header.manufacturer, "");
+
+        rom[0x134..0x144].copy_from_slice(b"SIXTEEN CHARS GB");
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "SIXTEEN CHARS GB");
+
+        // on cgb cartridges the code is split off
+        rom[0x134..0x144].copy_from_slice(b"COLOR GAMESAXYE\x80");
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "COLOR GAMES");
+        assert_eq!(header.manufacturer, "AXYE");
+    }
+
+    fn numbered_rom(banks:usize) -> Vec<u8> {
+        let mut rom = vec![0; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+        }
+        rom
+    }
+
+    #[test]
+    fn test_mbc1_rom_banking() {
+        let mut mbc = Mbc1::new(numbered_rom(128), 0);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write_rom(0x2000, 0x05);
+        assert_eq!(mbc.read_rom(0x4000), 5);
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(mbc.read_rom(0x4000), 0x21);
+        assert_eq!(mbc.read_rom(0x0000), 0);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(0x0000), 0x20);
+        mbc.write_rom(0x2000, 0x13);
+        mbc.write_rom(0x4000, 0x03);
+        assert_eq!(mbc.read_rom(0x4000), 0x73);
+    }
+
+    #[test]
+    fn test_mbc1_ram_banking() {
+        let mut mbc = Mbc1::new(numbered_rom(4), 0x8000);
+        mbc.write_ram(0xa000, 0x12);
+        assert_eq!(mbc.read_ram(0xa000), 0xff, "ram is disabled after reset");
+        mbc.write_rom(0x0000, 0x0a);
+        mbc.write_ram(0xa000, 0x12);
+        mbc.write_rom(0x4000, 0x02);
+        assert_eq!(mbc.read_ram(0xa000), 0x12, "mode 0 always uses ram bank 0");
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(0xa000), 0x00);
+        mbc.write_ram(0xa000, 0x34);
+        mbc.write_rom(0x4000, 0x00);
+        assert_eq!(mbc.read_ram(0xa000), 0x12);
+        mbc.write_rom(0x4000, 0x02);
+        assert_eq!(mbc.read_ram(0xa000), 0x34);
+    }
+
+    #[test]
+    fn test_mbc2() {
+        let mut mbc = Mbc2::new(numbered_rom(16));
+        mbc.write_rom(0x2000, 0x03);
+        assert_eq!(mbc.read_rom(0x4000), 1, "address bit 8 clear writes the ram enable register");
+        mbc.write_rom(0x2100, 0x03);
+        assert_eq!(mbc.read_rom(0x4000), 3);
+        mbc.write_rom(0x0100, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+
+        mbc.write_rom(0x0000, 0x0a);
+        mbc.write_ram(0xa005, 0xab);
+        assert_eq!(mbc.read_ram(0xa005), 0xfb);
+        assert_eq!(mbc.read_ram(0xa205), 0xfb, "ram is mirrored every 512 bytes");
+        assert_eq!(mbc.read_ram(0xbe05), 0xfb);
+        mbc.write_rom(0x0000, 0x00);
+        assert_eq!(mbc.read_ram(0xa005), 0xff);
+    }
+
+    fn read_rtc(mbc:&mut Mbc3) -> [u8;5] {
+        mbc.write_rom(0x6000, 0x00);
+        mbc.write_rom(0x6000, 0x01);
+        let mut regs = [0;5];
+        for (i, reg) in regs.iter_mut().enumerate() {
+            mbc.write_rom(0x4000, 0x08 + i as u8);
+            *reg = mbc.read_ram(0xa000);
+        }
+        regs
+    }
+
+    #[test]
+    fn test_mbc3_rom_and_ram_banking() {
+        let mut mbc = Mbc3::new(numbered_rom(128), 0x8000, None);
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write_rom(0x2000, 0x7f);
+        assert_eq!(mbc.read_rom(0x4000), 0x7f);
+        mbc.write_rom(0x0000, 0x0a);
+        mbc.write_rom(0x4000, 0x03);
+        mbc.write_ram(0xbfff, 0x56);
+        mbc.write_rom(0x4000, 0x00);
+        assert_eq!(mbc.read_ram(0xbfff), 0x00);
+        mbc.write_rom(0x4000, 0x03);
+        assert_eq!(mbc.read_ram(0xbfff), 0x56);
+    }
+
+    #[test]
+    fn test_mbc3_rtc() {
+        let time = Rc::new(Cell::new(1000));
+        let mut mbc = Mbc3::new(numbered_rom(4), 0, Some(Rtc::new(Box::new(FakeClock(time.clone())))));
+        mbc.write_rom(0x0000, 0x0a);
+        assert_eq!(read_rtc(&mut mbc), [0, 0, 0, 0, 0]);
+
+        time.set(1000 + 61);
+        mbc.write_rom(0x4000, 0x08);
+        assert_eq!(mbc.read_ram(0xa000), 0, "registers only change when latched");
+        assert_eq!(read_rtc(&mut mbc), [1, 1, 0, 0, 0]);
+
+        time.set(1000 + 61 + 256 * 86400 + 3600);
+        assert_eq!(read_rtc(&mut mbc), [1, 1, 1, 0, 1]);
+
+        mbc.write_rom(0x4000, 0x0c);
+        mbc.write_ram(0xa000, rtc_flags::HALT | rtc_flags::DAY_HIGH);
+        time.set(2000 + 300 * 86400);
+        assert_eq!(read_rtc(&mut mbc), [1, 1, 1, 0, 0x41], "halted clock does not advance");
+
+        mbc.write_rom(0x4000, 0x0c);
+        mbc.write_ram(0xa000, rtc_flags::DAY_HIGH);
+        mbc.write_rom(0x4000, 0x0b);
+        mbc.write_ram(0xa000, 0xff);
+        time.set(2000 + 300 * 86400 + 86400);
+        assert_eq!(read_rtc(&mut mbc), [1, 1, 1, 0, 0x80], "day counter overflow sets the carry bit");
+    }
+
+    #[test]
+    fn test_mbc5_banking() {
+        let mut mbc = Mbc5::new(numbered_rom(512), 0x20000, false);
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 0, "bank 0 can be mapped to 0x4000");
+        mbc.write_rom(0x2000, 0x05);
+        mbc.write_rom(0x3000, 0x01);
+        assert_eq!(mbc.read_rom(0x4000), 5, "bank 0x105");
+        assert_eq!(mbc.read_rom(0x4001), 0);
+        mbc.write_rom(0x0000, 0x0a);
+        mbc.write_rom(0x4000, 0x0f);
+        mbc.write_ram(0xa000, 0x78);
+        mbc.write_rom(0x4000, 0x07);
+        assert_eq!(mbc.read_ram(0xa000), 0x00);
+        mbc.write_rom(0x4000, 0x0f);
+        assert_eq!(mbc.read_ram(0xa000), 0x78);
+    }
+
+    #[test]
+    fn test_mbc5_rumble() {
+        let motor = Rc::new(Cell::new(false));
+        let mut mbc = Mbc5::new(numbered_rom(4), 0x8000, true);
+        let motor_out = motor.clone();
+        mbc.set_rumble_callback(Box::new(move |on| motor_out.set(on)));
+        mbc.write_rom(0x0000, 0x0a);
+        mbc.write_rom(0x4000, 0x09);
+        assert!(motor.get());
+        mbc.write_ram(0xa000, 0x11);
+        mbc.write_rom(0x4000, 0x01);
+        assert!(!motor.get());
+        assert_eq!(mbc.read_ram(0xa000), 0x11, "motor bit does not select a ram bank");
+    }
+
+    #[test]
+    fn test_mbc3_save_data() {
+        let time = Rc::new(Cell::new(5000));
+        let mut mbc = Mbc3::new(numbered_rom(4), 0x2000, Some(Rtc::new(Box::new(FakeClock(time.clone())))));
+        assert!(!mbc.take_dirty());
+        mbc.write_rom(0x0000, 0x0a);
+        mbc.write_ram(0xa010, 0x99);
+        mbc.write_rom(0x4000, 0x0a);
+        mbc.write_ram(0xa000, 0x05);
+        assert!(mbc.take_dirty());
+        assert!(!mbc.take_dirty());
+
+        let data = mbc.save_data();
+        assert_eq!(data.len(), 0x2000 + RTC_FOOTER_SIZE);
+        assert_eq!(data[0x10], 0x99);
+        assert_eq!(data[0x2000 + 8], 5, "hours register in the footer");
+        assert_eq!(data[0x2000 + 40..], 5000u64.to_le_bytes());
+
+        time.set(5000 + 3600);
+        let mut restored = Mbc3::new(numbered_rom(4), 0x2000, Some(Rtc::new(Box::new(FakeClock(time.clone())))));
+        restored.load_save_data(&data);
+        restored.write_rom(0x0000, 0x0a);
+        assert_eq!(restored.read_ram(0xa010), 0x99);
+        assert_eq!(read_rtc(&mut restored), [0, 0, 6, 0, 0], "clock advances by the time since saving");
+    }
+}
@@ -1,18 +1,44 @@
-use std::fs::File;
-use std::io::prelude::*;
+use std::fs::{self, File};
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
 
 
+use crate::bus::Bus;
 use crate::instructions::*;
+use crate::cartridge::{self, CartridgeError, CartridgeHeader, Mbc, RomOnly};
+use crate::ppu::Ppu;
+use crate::state::{StateError, StateReader, StateWriter};
 use Operation::*;
 
 pub struct Mmu {
     memory:[u8;0x10000],
-    rom:[u8;0x10000],
+    cartridge:Box<dyn Mbc>,
+    save_file:Option<PathBuf>,
     boot_rom:[u8;0x100],
     boot_rom_enable:bool,
     timer:Timer,
-    bank:u8,
+    serial:Serial,
+    ppu:Ppu,
+    dma:Dma,
     buttons:u8,
+    // KEY1 and double speed only exist for cgb cartridges
+    cgb:bool,
+    double_speed:bool,
+    speed_switch:bool,
+}
+
+// OAM DMA copies one byte per machine cycle, after a cycle to start up
+struct Dma {
+    source: u16,
+    // the byte copied next, -1 while starting, 0xa0 when done
+    index: isize,
+    cycles: isize,
+}
+
+impl Dma {
+    fn new() -> Dma {
+        Dma {source:0, index:0xa0, cycles:0}
+    }
 }
 
 pub struct Timer {
@@ -22,6 +48,12 @@ pub struct Timer {
     tima: u8,
 }
 
+impl Default for Timer {
+    fn default() -> Timer {
+        Timer::new()
+    }
+}
+
 impl Timer {
     pub fn new() -> Timer {
         Timer {div:0, tac:0, tma:0, tima:0}
@@ -58,45 +90,166 @@ impl Timer {
         }
         interrupt
     }
+
+    pub fn save_state(&self, state:&mut StateWriter) {
+        state.i64(self.div as i64);
+        state.u8(self.tac);
+        state.u8(self.tma);
+        state.u8(self.tima);
+    }
+
+    pub fn load_state(&mut self, state:&mut StateReader) -> Result<(), StateError> {
+        self.div = state.i64()? as isize;
+        self.tac = state.u8()?;
+        self.tma = state.u8()?;
+        self.tima = state.u8()?;
+        Ok(())
+    }
+}
+
+// The link port. Nothing is ever connected, so a transfer shifts in ones,
+// and only transfers on the internal clock finish. Every byte sent is
+// recorded, test roms print their results this way.
+pub struct Serial {
+    data: u8,
+    control: u8,
+    cycles_left: isize,
+    output: Vec<u8>,
+}
+
+impl Default for Serial {
+    fn default() -> Serial {
+        Serial::new()
+    }
+}
+
+impl Serial {
+    // 8 bits at 8192Hz
+    const TRANSFER_CYCLES:isize = 8 * 512;
+
+    pub fn new() -> Serial {
+        Serial {data:0, control:0, cycles_left:0, output:Vec::new()}
+    }
+
+    fn write_control(&mut self, value:u8) {
+        self.control = value;
+        if value & 0x81 == 0x81 {
+            self.output.push(self.data);
+            self.cycles_left = Serial::TRANSFER_CYCLES;
+        }
+    }
+
+    pub fn tick(&mut self, cycles: isize) -> bool {
+        if self.control & 0x81 != 0x81 {
+            return false;
+        }
+        self.cycles_left -= cycles;
+        if self.cycles_left > 0 {
+            return false;
+        }
+        self.data = 0xff;
+        self.control &= 0x7f;
+        true
+    }
+
+    // the bytes sent since the last take_output
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    pub fn save_state(&self, state:&mut StateWriter) {
+        state.u8(self.data);
+        state.u8(self.control);
+        state.i64(self.cycles_left as i64);
+    }
+
+    pub fn load_state(&mut self, state:&mut StateReader) -> Result<(), StateError> {
+        self.data = state.u8()?;
+        self.control = state.u8()?;
+        self.cycles_left = state.i64()? as isize;
+        Ok(())
+    }
+}
+
+impl Default for Mmu {
+    fn default() -> Mmu {
+        Mmu::new()
+    }
 }
 
 impl Mmu {
+    // During oam dma the cpu only reaches the io registers, hram and IE, the
+    // dma has the other buses. Vram and oam are cut off while the ppu uses
+    // them. Reads of what is cut off give 0xff, writes are dropped.
+    fn blocked(&self, address:u16) -> bool {
+        match address {
+            0x0000..=0xfeff if (0..0xa0).contains(&self.dma.index) => true,
+            0x8000..=0x9fff => !self.ppu.vram_accessible(),
+            0xfe00..=0xfe9f => !self.ppu.oam_accessible(),
+            _ => false,
+        }
+    }
+
     pub fn write(&mut self, address:u16, value:u8){
+        if self.blocked(address) {
+            return;
+        }
         match address {
+            0xff01 => {self.serial.data = value;},
+            0xff02 => self.serial.write_control(value),
             0xff04 => {self.timer.div = 0;},
             0xff05 => {self.timer.tima = value;},
             0xff06 => {self.timer.tma = value;},
             0xff07 => {self.timer.tac = value;},
-            0xff46 => { // DMA
-                let base = (value as u16) << 8;
-                for i in 0..0xa0 {
-                    self.memory[0xfe00 + i] = self.read(base + i as u16);
-                }
-            }
+            0xff46 => {
+                self.memory[address as usize] = value;
+                self.dma = Dma {source: (value as u16) << 8, index: -1, cycles: 0};
+            },
+            0x8000..=0x9fff | 0xfe00..=0xfe9f | 0xff40..=0xff45 | 0xff47..=0xff4b => self.ppu.write(address, value),
+            0xff4d if self.cgb => {self.speed_switch = value & 0x01 != 0;},
             0xff50 => {self.boot_rom_enable = false;},
-            0x2000..=0x3fff => {self.bank=value;},
-            0x0000..=0x7fff => (),
+            0x0000..=0x7fff => self.cartridge.write_rom(address, value),
+            0xa000..=0xbfff => self.cartridge.write_ram(address, value),
             _ => {self.memory[address as usize] = value;}
         }
     }
 
-    pub fn read(&self, address:u16) -> u8{
+    // a read by the cpu
+    pub fn read(&self, address:u16) -> u8 {
+        if self.blocked(address) {
+            return 0xff;
+        }
+        self.peek(address)
+    }
+
+    // reads whatever the cpu could reach right now, for the dma and debugging
+    pub fn peek(&self, address:u16) -> u8 {
         match address {
             0xff00 =>
                 (self.memory[address as usize] | 0xcf) &
                 (if self.memory[address as usize] & 0x10 == 0 {self.buttons | 0xF0} else {0xff}) &
                 (if self.memory[address as usize] & 0x20 == 0 {(self.buttons>>4) | 0xF0} else {0xff}),
+            // the upper three bits of IF are not used and read as 1
+            0xff0f => self.memory[address as usize] | 0xe0,
+            0xff01 => self.serial.data,
+            0xff02 => self.serial.control | 0x7e,
             0xff04 => ((self.timer.div & 0xff00) >> 8) as u8,
             0xff05 => self.timer.tima,
             0xff06 => self.timer.tma,
             0xff07 => self.timer.tac,
+            0xff4d if self.cgb => (self.double_speed as u8) << 7 | 0x7e | self.speed_switch as u8,
             0x0000..=0x00ff => if self.boot_rom_enable {
                 self.boot_rom[address as usize]
             } else {
-                self.rom[address as usize]
+                self.cartridge.read_rom(address)
             },
-            0x0000..=0x3fff => self.rom[address as usize],
-            0x4000..=0x7fff => self.rom[(address & 0x3fff) as usize + self.bank as usize * 0x4000],
+            0x0000..=0x7fff => self.cartridge.read_rom(address),
+            0xa000..=0xbfff => self.cartridge.read_ram(address),
+            0x8000..=0x9fff | 0xfe00..=0xfe9f | 0xff40..=0xff45 | 0xff47..=0xff4b => self.ppu.read(address),
             _ => self.memory[address as usize],
         }
     }
@@ -104,45 +257,199 @@ impl Mmu {
     pub fn new() -> Mmu {
         Mmu {
             memory:[0xff;0x10000],
-            rom:[0xff;0x10000],
+            cartridge:Box::new(RomOnly::new(vec![0xff;0x8000], 0)),
+            save_file:None,
             boot_rom:[0xff;0x100],
             boot_rom_enable:true,
             timer:Timer::new(),
-            bank:1,
+            serial:Serial::new(),
+            ppu:Ppu::new(),
+            dma:Dma::new(),
             buttons:0xff,
+            cgb:false,
+            double_speed:false,
+            speed_switch:false,
          }
     }
 
-    pub fn load(&mut self, filename: &str, base:u16) {
-        let mut f = File::open(filename).expect("file not found");
+    pub fn load(&mut self, filename: &str) -> Result<CartridgeHeader, CartridgeError> {
+        let mut f = File::open(filename)?;
         let mut data = Vec::new();
-        f.read_to_end(&mut data).expect("error reading file");
-        for (index, value) in data.iter().enumerate() {
-            self.rom[index + base as usize] = *value;
+        f.read_to_end(&mut data)?;
+        let header = CartridgeHeader::parse(&data)?;
+        self.cartridge = cartridge::create(&header, data)?;
+        self.cgb = header.cgb_flag & 0x80 != 0;
+        self.save_file = None;
+        if header.has_battery() {
+            let path = Path::new(filename).with_extension("sav");
+            match fs::read(&path) {
+                Ok(save) => self.cartridge.load_save_data(&save),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(err) => return Err(err.into()),
+            }
+            self.save_file = Some(path);
         }
+        Ok(header)
     }
 
-    pub fn load_boot_rom(&mut self, filename: &str) {
-        let mut f = File::open(filename).expect("file not found");
-        let mut data = Vec::new();
-        f.read_to_end(&mut data).expect("error reading file");
-        for (index, value) in data.iter().enumerate() {
-            self.boot_rom[index] = *value;
+    // writes battery backed cartridge ram to the .sav file if it changed
+    pub fn save_ram(&mut self) -> io::Result<()> {
+        if let Some(path) = &self.save_file {
+            if self.cartridge.take_dirty() {
+                fs::write(path, self.cartridge.save_data())?;
+            }
         }
+        Ok(())
+    }
+
+    // the dmg boot rom is exactly 256 bytes
+    pub fn load_boot_rom(&mut self, filename: &str) -> io::Result<()> {
+        let data = fs::read(filename)?;
+        if data.len() != self.boot_rom.len() {
+            let message = format!("boot rom has {} bytes, expected {}", data.len(), self.boot_rom.len());
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        self.boot_rom.copy_from_slice(&data);
+        Ok(())
     }
 
     pub fn flag_interrupt(&mut self, irq:u8){
         self.write(0xff0f, irq | self.read(0xff0f));
     }
 
+    fn run_dma(&mut self, cycles:isize) {
+        self.dma.cycles += cycles;
+        while self.dma.index < 0xa0 && self.dma.cycles >= 4 {
+            self.dma.cycles -= 4;
+            if self.dma.index >= 0 {
+                let offset = self.dma.index as u16;
+                let value = self.peek(self.dma.source + offset);
+                self.ppu.write(0xfe00 + offset, value);
+            }
+            self.dma.index += 1;
+        }
+    }
+
+    pub fn idle(&mut self, cycles:isize) {
+        self.ppu.idle(if self.double_speed {cycles / 2} else {cycles});
+    }
+
     pub fn tick(&mut self, cycles:isize){
+        self.run_dma(cycles);
+        // in double speed the ppu keeps its pace while everything else runs twice as fast
+        let irq = self.ppu.tick(if self.double_speed {cycles / 2} else {cycles});
+        if irq != 0 {
+            self.flag_interrupt(irq);
+        }
         if self.timer.tick(cycles){
             self.flag_interrupt(0x04);
         }
+        if self.serial.tick(cycles){
+            self.flag_interrupt(0x08);
+        }
     }
 
     pub fn set_buttons(&mut self, buttons:u8) {
+        // a selected line going low raises the joypad interrupt
+        let lines = self.read(0xff00);
         self.buttons = !buttons;
+        if lines & !self.read(0xff00) & 0x0f != 0 {
+            self.flag_interrupt(0x10);
+        }
+    }
+
+    // resets DIV, then does the speed switch if one was prepared in KEY1
+    pub fn stop(&mut self) -> bool {
+        self.timer.div = 0;
+        if self.cgb && self.speed_switch {
+            self.speed_switch = false;
+            self.double_speed = !self.double_speed;
+            return true;
+        }
+        false
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    pub fn set_rumble_callback(&mut self, callback:Box<dyn FnMut(bool)>) {
+        self.cartridge.set_rumble_callback(callback);
+    }
+
+    pub fn timer(&self) -> &Timer {
+        &self.timer
+    }
+
+    pub fn timer_mut(&mut self) -> &mut Timer {
+        &mut self.timer
+    }
+
+    pub fn serial(&self) -> &Serial {
+        &self.serial
+    }
+
+    pub fn serial_mut(&mut self) -> &mut Serial {
+        &mut self.serial
+    }
+
+    pub fn ppu(&self) -> &Ppu {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut Ppu {
+        &mut self.ppu
+    }
+
+    pub fn cartridge(&self) -> &dyn Mbc {
+        self.cartridge.as_ref()
+    }
+
+    pub fn cartridge_mut(&mut self) -> &mut dyn Mbc {
+        self.cartridge.as_mut()
+    }
+
+    pub fn save_state(&self, state:&mut StateWriter) {
+        state.bytes(&self.memory);
+        state.bool(self.boot_rom_enable);
+        state.u16(self.dma.source);
+        state.i64(self.dma.index as i64);
+        state.i64(self.dma.cycles as i64);
+        state.bool(self.double_speed);
+        state.bool(self.speed_switch);
+    }
+
+    pub fn load_state(&mut self, state:&mut StateReader) -> Result<(), StateError> {
+        state.bytes_into(&mut self.memory)?;
+        self.boot_rom_enable = state.bool()?;
+        self.dma.source = state.u16()?;
+        self.dma.index = state.i64()? as isize;
+        self.dma.cycles = state.i64()? as isize;
+        self.double_speed = state.bool()?;
+        self.speed_switch = state.bool()?;
+        Ok(())
+    }
+}
+
+impl Bus for Mmu {
+    fn read(&self, address:u16) -> u8 {
+        Mmu::read(self, address)
+    }
+
+    fn write(&mut self, address:u16, value:u8) {
+        Mmu::write(self, address, value)
+    }
+
+    fn tick(&mut self, cycles:isize) {
+        Mmu::tick(self, cycles)
+    }
+
+    fn idle(&mut self, cycles:isize) {
+        Mmu::idle(self, cycles)
+    }
+
+    fn stop(&mut self) -> bool {
+        Mmu::stop(self)
     }
 }
 
@@ -151,16 +458,38 @@ pub const FLAG_N:u8 = 1<<6;
 pub const FLAG_H:u8 = 1<<5;
 pub const FLAG_C:u8 = 1<<4;
 
-pub struct Cpu {
-    pub mmu: Mmu,
+// things the cpu tells whoever drives it, see Cpu::take_event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuEvent {
+    // LD B,B was executed, the mooneye test roms end with it
+    SoftwareBreakpoint,
+    // one of the 11 unused opcodes was fetched, the cpu locked up
+    IllegalOpcode {address:u16, opcode:u8},
+}
+
+pub struct Cpu<B:Bus = Mmu> {
+    pub bus: B,
     pub a:u8, pub f:u8,
     pub b:u8, pub c:u8,
     pub d:u8, pub e:u8,
     pub h:u8, pub l:u8,
     pub sp: u16,
     pub pc: u16,
-    pub ie: bool,
+    // interrupt master enable
+    pub ime: bool,
+    // EI takes effect after the next instruction
+    ime_pending: bool,
     pub hlt: bool,
+    // STOP halts the clock until a button is pressed
+    pub stopped: bool,
+    // HALT with IME off and an interrupt pending does not halt, but the
+    // next fetch fails to increment pc
+    halt_bug: bool,
+    // an illegal opcode hangs the cpu until reset, the rest keeps running
+    pub locked: bool,
+    event: Option<CpuEvent>,
+    // cycles of the current step so far
+    cycles: isize,
 }
 
 pub fn word(h:u8, l:u8) -> u16 {
@@ -239,6 +568,8 @@ fn daa(a:u8, nf: bool, cf:bool, hf: bool, cf_out: &mut bool) -> u8 {
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
+    use crate::bus::FlatBus;
+    use crate::ppu::CYCLES_PER_FRAME;
 
     #[test]
     fn test_add() {
@@ -297,6 +628,28 @@ mod tests {
         assert_eq!(h, true);
     }
 
+    #[test]
+    fn test_jr() {
+        let mut cpu = Cpu::new(Mmu::new());
+        // offsets count from the next instruction: JR -2 jumps onto itself,
+        // JR NZ,-6 at 0xc004 goes back to 0xc000
+        cpu.bus.write(0xc000, 0x18);
+        cpu.bus.write(0xc001, 0xfe);
+        cpu.bus.write(0xc004, 0x20);
+        cpu.bus.write(0xc005, 0xfa);
+        cpu.pc = 0xc000;
+        cpu.step();
+        assert_eq!(cpu.pc, 0xc000);
+        cpu.pc = 0xc004;
+        cpu.f = 0;
+        cpu.step();
+        assert_eq!(cpu.pc, 0xc000);
+        // forward
+        cpu.bus.write(0xc001, 0x7f);
+        cpu.step();
+        assert_eq!(cpu.pc, 0xc081);
+    }
+
     #[test]
     fn test_daa() {
         let mut c: bool = false;
@@ -305,21 +658,254 @@ mod tests {
         assert_eq!(c, false, "daa 0x00 NH expect carry flag reset");
     }
 
+    #[test]
+    fn test_serial() {
+        let mut mmu = Mmu::new();
+        mmu.write(0xff0f, 0x00);
+        mmu.write(0xff01, b'P');
+        mmu.write(0xff02, 0x81);
+        assert_eq!(mmu.serial().output(), b"P");
+        mmu.tick(4000);
+        assert_eq!(mmu.read(0xff02), 0xff);
+        mmu.tick(96);
+        assert_eq!(mmu.read(0xff02), 0x7f);
+        assert_eq!(mmu.read(0xff01), 0xff);
+        assert_eq!(mmu.read(0xff0f) & 0x08, 0x08);
+
+        // nobody provides the clock on the other side
+        mmu.write(0xff02, 0x80);
+        mmu.tick(10000);
+        assert_eq!(mmu.read(0xff02), 0xfe);
+        assert_eq!(mmu.serial_mut().take_output(), b"P");
+    }
+
+    #[test]
+    fn test_dma() {
+        let mut mmu = Mmu::new();
+        mmu.write(0xc000, 0x12);
+        mmu.write(0xc09f, 0x34);
+        mmu.write(0xff46, 0xc0);
+        // a machine cycle to start, then a byte per machine cycle
+        mmu.tick(4);
+        assert_eq!(mmu.peek(0xfe00), 0x00);
+        mmu.tick(4);
+        assert_eq!(mmu.peek(0xfe00), 0x12);
+        assert_eq!(mmu.peek(0xfe01), 0x00);
+        // meanwhile the cpu only reaches the io registers and hram
+        assert_eq!(mmu.read(0xc000), 0xff);
+        mmu.write(0xc000, 0x56);
+        mmu.write(0xff80, 0x78);
+        assert_eq!(mmu.read(0xff80), 0x78);
+        mmu.tick(0x9f * 4);
+        assert_eq!(mmu.read(0xfe9f), 0x34);
+        assert_eq!(mmu.read(0xc000), 0x12);
+    }
+
+    #[test]
+    fn test_ppu_access() {
+        let mut mmu = Mmu::new();
+        mmu.write(0x8000, 0x12);
+        mmu.write(0xfe00, 0x34);
+        mmu.write(0xff40, 0x80);
+        // the first line after switching on has no oam search
+        while mmu.ppu().mode != 2 {
+            mmu.tick(4);
+        }
+        assert_eq!(mmu.read(0x8000), 0x12);
+        assert_eq!(mmu.read(0xfe00), 0xff);
+        mmu.write(0xfe00, 0x56);
+        while mmu.ppu().mode != 3 {
+            mmu.tick(4);
+        }
+        assert_eq!(mmu.read(0x8000), 0xff);
+        mmu.write(0x8000, 0x78);
+        while mmu.ppu().mode != 0 {
+            mmu.tick(4);
+        }
+        assert_eq!(mmu.read(0x8000), 0x12);
+        assert_eq!(mmu.read(0xfe00), 0x34);
+    }
+
+    #[test]
+    fn test_load_boot_rom() {
+        let mut mmu = Mmu::new();
+        let dir = env!("CARGO_MANIFEST_DIR");
+        mmu.load_boot_rom(&format!("{}/RBOY_ROM.bin", dir)).unwrap();
+        let err = mmu.load_boot_rom(&format!("{}/Cargo.toml", dir)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = mmu.load_boot_rom(&format!("{}/missing.bin", dir)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    // EI; NOP; NOP with the timer and vblank interrupts pending
+    fn interrupt_cpu() -> Cpu<FlatBus> {
+        let mut cpu = Cpu::new(FlatBus::new());
+        cpu.bus.memory[0xc000..0xc003].copy_from_slice(&[0xfb, 0x00, 0x00]);
+        cpu.bus.memory[0xffff] = 0x05;
+        cpu.bus.memory[0xff0f] = 0x05;
+        cpu.pc = 0xc000;
+        cpu.sp = 0xd000;
+        cpu
+    }
+
+    #[test]
+    fn test_interrupt_dispatch() {
+        let mut cpu = interrupt_cpu();
+        // EI takes effect after the next instruction
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.pc, 0xc001);
+        assert_eq!(cpu.step(), 4 + 20);
+        // vblank goes first, the timer stays pending
+        assert_eq!(cpu.pc, 0x0040);
+        assert_eq!(cpu.sp, 0xcffe);
+        assert_eq!(cpu.bus.memory[0xcffe], 0x02);
+        assert_eq!(cpu.bus.memory[0xcfff], 0xc0);
+        assert_eq!(cpu.bus.memory[0xff0f], 0x04);
+        assert!(!cpu.ime);
+    }
+
+    #[test]
+    fn test_interrupt_cancelled() {
+        // the high byte of pc lands on IE and disables both interrupts
+        let mut cpu = interrupt_cpu();
+        cpu.sp = 0x0000;
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0000);
+        assert_eq!(cpu.bus.memory[0xffff], 0xc0);
+        assert_eq!(cpu.bus.memory[0xff0f], 0x05);
+    }
+
+    #[test]
+    fn test_halt_bug() {
+        // HALT; INC A with interrupts disabled but one pending
+        let mut cpu = Cpu::new(FlatBus::new());
+        cpu.bus.memory[0xc000..0xc002].copy_from_slice(&[0x76, 0x3c]);
+        cpu.bus.memory[0xffff] = 0x01;
+        cpu.bus.memory[0xff0f] = 0x01;
+        cpu.pc = 0xc000;
+        cpu.step();
+        assert!(!cpu.hlt);
+        cpu.step();
+        assert_eq!(cpu.pc, 0xc001);
+        cpu.step();
+        assert_eq!(cpu.pc, 0xc002);
+        assert_eq!(cpu.a, 2);
+    }
+
+    #[test]
+    fn test_illegal_opcode() {
+        let mut cpu = Cpu::new(FlatBus::new());
+        cpu.bus.memory[0xc000] = 0xd3;
+        cpu.bus.memory[0xffff] = 0x01;
+        cpu.bus.memory[0xff0f] = 0x01;
+        cpu.pc = 0xc000;
+        cpu.ime = true;
+        cpu.step();
+        assert_eq!(cpu.take_event(), Some(CpuEvent::IllegalOpcode {address: 0xc000, opcode: 0xd3}));
+        // not even an interrupt gets it going again
+        for _ in 0..10 {
+            assert_eq!(cpu.step(), 4);
+        }
+        assert!(cpu.locked);
+        assert_eq!(cpu.pc, 0xc001);
+        assert_eq!(cpu.take_event(), None);
+    }
+
+    // STOP; NOP in work ram
+    fn stop_cpu() -> Cpu {
+        let mut cpu = Cpu::new(Mmu::new());
+        cpu.bus.write(0xc000, 0x10);
+        cpu.bus.write(0xc001, 0x00);
+        cpu.bus.write(0xff00, 0x10);
+        cpu.pc = 0xc000;
+        cpu
+    }
+
+    #[test]
+    fn test_stop() {
+        let mut cpu = stop_cpu();
+        cpu.bus.tick(0x400);
+        cpu.step();
+        assert!(cpu.stopped);
+        assert_eq!(cpu.bus.timer().div, 0);
+        // the clock stands still
+        for _ in 0..100 {
+            cpu.step();
+        }
+        assert_eq!(cpu.bus.timer().div, 0);
+        assert_eq!(cpu.pc, 0xc002);
+        // frames are still counted for whoever waits for the next one
+        let (frames, dot) = (cpu.bus.ppu().frames, cpu.bus.ppu().dot);
+        for _ in 0..CYCLES_PER_FRAME / 4 {
+            cpu.step();
+        }
+        assert_eq!(cpu.bus.ppu().frames, frames + 1);
+        assert_eq!(cpu.bus.ppu().dot, dot);
+
+        // buttons that are not selected do not wake it
+        cpu.bus.set_buttons(0x01);
+        cpu.step();
+        assert!(cpu.stopped);
+        cpu.bus.set_buttons(0x81);
+        assert_eq!(cpu.bus.read(0xff0f) & 0x10, 0x10);
+        cpu.step();
+        assert!(!cpu.stopped);
+    }
+
+    #[test]
+    fn test_speed_switch() {
+        let mut cpu = stop_cpu();
+        assert_eq!(cpu.bus.read(0xff4d), 0xff);
+        cpu.bus.cgb = true;
+        cpu.bus.write(0xff4d, 0x01);
+        assert_eq!(cpu.bus.read(0xff4d), 0x7f);
+        cpu.step();
+        assert!(!cpu.stopped);
+        assert!(cpu.bus.double_speed());
+        assert_eq!(cpu.bus.read(0xff4d), 0xfe);
+    }
+
 
 }
 
-impl Cpu {
+impl<B:Bus> Cpu<B> {
+    // Every memory access takes a machine cycle of 4 clocks. The rest of the
+    // hardware runs through the cycle first, so the access sees its state
+    // at the end of the cycle.
+    fn read_cycle(&mut self, address:u16) -> u8 {
+        self.internal_cycle();
+        self.bus.read(address)
+    }
+
+    fn write_cycle(&mut self, address:u16, value:u8) {
+        self.internal_cycle();
+        self.bus.write(address, value);
+    }
+
+    // a machine cycle without memory access
+    fn internal_cycle(&mut self) {
+        self.bus.tick(4);
+        self.cycles += 4;
+    }
+
     fn fetch(&mut self) -> u8 {
-        let val = self.mmu.read(self.pc);
-        self.pc += 1;
+        let val = self.read_cycle(self.pc);
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.pc = self.pc.wrapping_add(1);
+        }
         val
     }
 
-    fn fetch_and_decode(&mut self) -> (Instruction, Immediate) {
-        let mut instr = INSTRUCTIONS[self.fetch() as usize];
+    // also returns the opcode, 0xcb for prefixed instructions
+    fn fetch_and_decode(&mut self) -> (u8, Instruction, Immediate) {
+        let opcode = self.fetch();
+        let mut instr = INSTRUCTIONS[opcode as usize];
         if instr.operation == PREFIX {
             instr = INSTRUCTIONS[self.fetch() as usize + 0x100];
-            (instr, Immediate::None)
+            (opcode, instr, Immediate::None)
         }
         else {
             let imm = match instr.length {
@@ -328,7 +914,7 @@ impl Cpu {
                 3 => {let l = self.fetch(); Immediate::Imm16(word(self.fetch(), l))},
                 _ => panic!("Unecpected instruction length")
             };
-            (instr, imm)
+            (opcode, instr, imm)
         }
     }
 
@@ -347,7 +933,7 @@ impl Cpu {
 
         let addr = match imm {
             Immediate::Imm16(addr) => addr,
-            Immediate::Imm8(offset) => if offset < 128 {self.pc.wrapping_add(offset as u16)} else {self.pc.wrapping_add(offset as u16 - 0x100)},
+            Immediate::Imm8(offset) => self.pc.wrapping_add(offset as i8 as u16),
             Immediate::None => if op == JP {word(self.h, self.l)} else {rst_target as u16},
         };
 
@@ -357,25 +943,26 @@ impl Cpu {
             },
             CALL | RST => {
                 let [pch, pcl] = self.pc.to_be_bytes();
-                self.sp -= 1;
-                self.mmu.write(self.sp, pch);
-                self.sp -= 1;
-                self.mmu.write(self.sp, pcl);
+                self.internal_cycle();
+                self.sp = self.sp.wrapping_sub(1);
+                self.write_cycle(self.sp, pch);
+                self.sp = self.sp.wrapping_sub(1);
+                self.write_cycle(self.sp, pcl);
                 self.pc = addr;
             },
             RET | RETI => {
-                let pcl = self.mmu.read(self.sp);
-                self.sp += 1;
-                let pch = self.mmu.read(self.sp);
-                self.sp += 1;
+                let pcl = self.read_cycle(self.sp);
+                self.sp = self.sp.wrapping_add(1);
+                let pch = self.read_cycle(self.sp);
+                self.sp = self.sp.wrapping_add(1);
                 self.pc = word(pch, pcl);
                 // TODO handle signalling of completion of interrupt handler for RETI
-                if op == RETI {self.ie = true;}
+                if op == RETI {self.ime = true;}
             },
         }
     }
 
-    fn readloc8(&self, loc:Location8, imm:Immediate) -> u8 {
+    fn readloc8(&mut self, loc:Location8, imm:Immediate) -> u8 {
         use Location8::*;
         match loc {
             Empty => 0,
@@ -388,13 +975,13 @@ impl Cpu {
             L => self.l,
             IMM8 => match imm {Immediate::Imm8(i) => i,
                         _ => panic!("Expect IMM8!")},
-            ADDR_BC => self.mmu.read(word(self.b, self.c)),
-            ADDR_DE => self.mmu.read(word(self.d, self.e)),
-            ADDR_HL | ADDR_HL_DEC | ADDR_HL_INC => self.mmu.read(word(self.h, self.l)),
-            ADDR_IMM16 => match imm {Immediate::Imm16(a) => self.mmu.read(a),
+            ADDR_BC => self.read_cycle(word(self.b, self.c)),
+            ADDR_DE => self.read_cycle(word(self.d, self.e)),
+            ADDR_HL | ADDR_HL_DEC | ADDR_HL_INC => self.read_cycle(word(self.h, self.l)),
+            ADDR_IMM16 => match imm {Immediate::Imm16(a) => self.read_cycle(a),
                         _ => panic!("Expect IMM16!")},
-            ADDR_C => self.mmu.read(word(0xFF, self.c)),
-            ADDR_IMM8 => match imm {Immediate::Imm8(i) => self.mmu.read(word(0xFF, i)),
+            ADDR_C => self.read_cycle(word(0xFF, self.c)),
+            ADDR_IMM8 => match imm {Immediate::Imm8(i) => self.read_cycle(word(0xFF, i)),
                         _ => panic!("Expect IMM8!")},
         }
     }
@@ -411,20 +998,21 @@ impl Cpu {
             H => {self.h = value;},
             L => {self.l = value;},
             IMM8 => panic!("Illegal destination IMM8!"),
-            ADDR_BC => self.mmu.write(word(self.b, self.c), value),
-            ADDR_DE => self.mmu.write(word(self.d, self.e), value),
-            Location8::ADDR_HL | Location8::ADDR_HL_DEC | Location8::ADDR_HL_INC => self.mmu.write(word(self.h, self.l), value),
-            ADDR_IMM16 => match imm {Immediate::Imm16(a) => self.mmu.write(a,value),
+            ADDR_BC => self.write_cycle(word(self.b, self.c), value),
+            ADDR_DE => self.write_cycle(word(self.d, self.e), value),
+            Location8::ADDR_HL | Location8::ADDR_HL_DEC | Location8::ADDR_HL_INC => self.write_cycle(word(self.h, self.l), value),
+            ADDR_IMM16 => match imm {Immediate::Imm16(a) => self.write_cycle(a,value),
                         _ => panic!("Expect IMM16!")},
-            ADDR_C => self.mmu.write(word(0xFF, self.c), value),
-            ADDR_IMM8 => match imm {Immediate::Imm8(i) => self.mmu.write(word(0xFF, i), value),
+            ADDR_C => self.write_cycle(word(0xFF, self.c), value),
+            ADDR_IMM8 => match imm {Immediate::Imm8(i) => self.write_cycle(word(0xFF, i), value),
                         _ => panic!("Expect IMM8!")},
         }
     }
 
     fn data8(&mut self, op: OpData, dst:Location8, src:Location8, z:FlagOp, n:FlagOp, h:FlagOp, c:FlagOp, bit:u8, imm:Immediate) {
         let s = self.readloc8(src, imm);
-        let d = self.readloc8(dst, imm);
+        // a load does not read its destination
+        let d = if op == OpData::LD {0} else {self.readloc8(dst, imm)};
         let c_in:u8 = if self.f & FLAG_C == 0 {0} else {1};
 
         let mut hf_out = false;
@@ -488,18 +1076,18 @@ impl Cpu {
 
         use Location8::{ADDR_HL_INC, ADDR_HL_DEC};
         if src == ADDR_HL_INC || dst == ADDR_HL_INC {
-            let [h, l] = (word(self.h, self.l) + 1).to_be_bytes();
+            let [h, l] = word(self.h, self.l).wrapping_add(1).to_be_bytes();
             self.h = h;
             self.l = l;
         }
         if src == ADDR_HL_DEC || dst == ADDR_HL_DEC {
-            let [h, l] = (word(self.h, self.l) - 1).to_be_bytes();
+            let [h, l] = word(self.h, self.l).wrapping_sub(1).to_be_bytes();
             self.h = h;
             self.l = l;
         }
     }
 
-    fn readloc16(&self, loc:Location16, imm:Immediate) -> u16 {
+    fn readloc16(&mut self, loc:Location16, imm:Immediate) -> u16 {
         use Location16::*;
         match loc {
             Empty_W | ADDR_SP_DEC => 0,
@@ -510,8 +1098,14 @@ impl Cpu {
             SP => self.sp,
             IMM16 => match imm {Immediate::Imm16(i) => i,
                         _ => panic!("Expect IMM16!")},
-            ADDR_SP_INC => word(self.mmu.read(self.sp+1), self.mmu.read(self.sp)),
-            ADDR_IMM16_W => match imm {Immediate::Imm16(a) => word(self.mmu.read(a+1), self.mmu.read(a)),
+            ADDR_SP_INC => {
+                let l = self.read_cycle(self.sp);
+                word(self.read_cycle(self.sp.wrapping_add(1)), l)
+            },
+            ADDR_IMM16_W => match imm {Immediate::Imm16(a) => {
+                            let l = self.read_cycle(a);
+                            word(self.read_cycle(a.wrapping_add(1)), l)
+                        },
                         _ => panic!("Expect IMM16!")},
         }
     }
@@ -526,15 +1120,19 @@ impl Cpu {
             HL => {self.h = vh; self.l = vl;},
             SP => {self.sp = value;}
             Empty_W | IMM16 | ADDR_SP_INC => panic!("Illegal destination IMM!"),
-            ADDR_SP_DEC => {self.mmu.write(self.sp-1, vh); self.mmu.write(self.sp-2, vl);},
-            ADDR_IMM16_W => match imm {Immediate::Imm16(a) => {self.mmu.write(a+1, vh); self.mmu.write(a, vl);},
+            ADDR_SP_DEC => {
+                self.internal_cycle();
+                self.write_cycle(self.sp.wrapping_sub(1), vh);
+                self.write_cycle(self.sp.wrapping_sub(2), vl);
+            },
+            ADDR_IMM16_W => match imm {Immediate::Imm16(a) => {self.write_cycle(a, vl); self.write_cycle(a.wrapping_add(1), vh);},
                         _ => panic!("Expect IMM16!")},
         }
     }
 
     fn data16(&mut self, op: OpData, dst:Location16, src:Location16, z:FlagOp, n:FlagOp, h:FlagOp, c:FlagOp, imm:Immediate) {
         let s = self.readloc16(src, imm);
-        let d = self.readloc16(dst, imm);
+        let d = if op == OpData::LD {0} else {self.readloc16(dst, imm)};
 
         let mut hf_out = false;
         let mut cf_out = false;
@@ -577,39 +1175,131 @@ impl Cpu {
         self.writeloc16(dst, imm, r);
 
         if src == Location16::ADDR_SP_INC {
-            self.sp += 2;
+            self.sp = self.sp.wrapping_add(2);
         }
         if dst == Location16::ADDR_SP_DEC {
-            self.sp -= 2;
+            self.sp = self.sp.wrapping_sub(2);
         }
 
     }
 
-    pub fn new(mmu:Mmu) -> Cpu {
-        Cpu{mmu:mmu,
+    pub fn new(bus:B) -> Cpu<B> {
+        Cpu{bus,
             a:0, f:0,
             b:0, c:0,
             d:0, e:0,
             h:0, l:0,
             sp:0,
             pc:0,
-            ie:false,
+            ime:false,
+            ime_pending:false,
             hlt:false,
+            stopped:false,
+            halt_bug:false,
+            locked:false,
+            event:None,
+            cycles:0,
+        }
+    }
+
+    pub fn save_state(&self, state:&mut StateWriter) {
+        for &reg in &[self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l] {
+            state.u8(reg);
         }
+        state.u16(self.sp);
+        state.u16(self.pc);
+        state.bool(self.ime);
+        state.bool(self.ime_pending);
+        state.bool(self.hlt);
+        state.bool(self.stopped);
+        state.bool(self.halt_bug);
+        state.bool(self.locked);
     }
 
+    pub fn load_state(&mut self, state:&mut StateReader) -> Result<(), StateError> {
+        for reg in &mut [&mut self.a, &mut self.f, &mut self.b, &mut self.c, &mut self.d, &mut self.e, &mut self.h, &mut self.l] {
+            **reg = state.u8()?;
+        }
+        self.sp = state.u16()?;
+        self.pc = state.u16()?;
+        self.ime = state.bool()?;
+        self.ime_pending = state.bool()?;
+        self.hlt = state.bool()?;
+        self.stopped = state.bool()?;
+        self.halt_bug = state.bool()?;
+        self.locked = state.bool()?;
+        Ok(())
+    }
+
+    // the event of the last step, if any
+    pub fn take_event(&mut self) -> Option<CpuEvent> {
+        self.event.take()
+    }
+
+    // Takes 5 machine cycles: two to wait, two to push pc and one to jump.
+    // Which interrupt is serviced is decided only after the high byte of pc
+    // is pushed. If that push overwrote IE and nothing is left to service,
+    // the cpu jumps to 0x0000 instead.
+    fn dispatch_interrupt(&mut self) {
+        self.ime = false;
+        self.internal_cycle();
+        self.internal_cycle();
+        let [pch, pcl] = self.pc.to_be_bytes();
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_cycle(self.sp, pch);
+        let irq = self.bus.pending_interrupts();
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_cycle(self.sp, pcl);
+        if irq == 0 {
+            self.pc = 0x0000;
+        } else {
+            // the lowest bit has the highest priority, only its flag is cleared
+            let bit = irq.trailing_zeros();
+            self.bus.acknowledge_interrupt(bit);
+            self.pc = 0x0040 + 8 * bit as u16;
+        }
+        self.internal_cycle();
+    }
+
+    // runs one instruction machine cycle by machine cycle, returns the cycles taken
     pub fn step(&mut self) -> isize {
-        let mut cycles = 0;
-        if self.hlt {
-            cycles = 4;
+        self.cycles = 0;
+        // the instruction after EI runs with interrupts still disabled, the
+        // check for them comes only after it
+        if self.ime_pending {
+            self.ime_pending = false;
+            self.ime = true;
+        }
+        if self.stopped {
+            // nothing behind the bus runs until a selected button is pressed
+            self.cycles += 4;
+            self.bus.idle(4);
+            if self.bus.read(0xff00) & 0x0f != 0x0f {
+                self.stopped = false;
+            }
+            return self.cycles;
+        }
+        if self.hlt || self.locked {
+            self.internal_cycle();
         }
         else {
-            let (instr, imm) = self.fetch_and_decode();
+            let address = self.pc;
+            let (opcode, instr, imm) = self.fetch_and_decode();
+            if opcode == 0x40 {
+                self.event = Some(CpuEvent::SoftwareBreakpoint);
+            }
+            let mut duration = instr.cycles as isize;
 
             match instr.operation {
                 DATA16 {op, dst, src, z, n, h, c, } => self.data16(op, dst, src, z, n, h, c, imm),
                 DATA8 {op, dst, src, z, n, h, c, bit} => self.data8(op, dst, src, z, n, h, c, bit, imm),
-                JUMP  {op, cond, rst_target} => if self.condition_satisfied(cond) {self.jump(op, rst_target, imm)} else {cycles += instr.cycles_nj as isize - instr.cycles as isize;},
+                JUMP  {op, cond, rst_target} => {
+                    // a conditional return takes a cycle to check the condition
+                    if op == OpJump::RET && cond != JumpCondition::ALWAYS {
+                        self.internal_cycle();
+                    }
+                    if self.condition_satisfied(cond) {self.jump(op, rst_target, imm)} else {duration = instr.cycles_nj as isize;}
+                },
                 SPIMM8 {dst} => {
                     let sp = self.sp as u32 | 0x01000000;
                     let offset = match imm {Immediate::Imm8(i) => i as u32,
@@ -628,30 +1318,38 @@ impl Cpu {
                 PREFIX => panic!("PREFIX must not occur after decoding."),
                 SCF => {self.f = (self.f & !FLAG_H & !FLAG_N) | FLAG_C;},
                 CCF => {self.f = (self.f & !FLAG_H & !FLAG_N) ^ FLAG_C;},
-                DI => {self.ie = false;},
-                EI => {self.ie = true;},
-                HALT | STOP => {self.hlt = true;},
+                DI => {self.ime = false; self.ime_pending = false;},
+                EI => {self.ime_pending = !self.ime;},
+                HALT => {
+                    if self.ime || self.bus.pending_interrupts() == 0 {
+                        self.hlt = true;
+                    } else {
+                        self.halt_bug = true;
+                    }
+                },
+                STOP => {
+                    if !self.bus.stop() {
+                        self.stopped = true;
+                    }
+                },
                 NOP => (),
-                UNDEF => panic!("UNDEF instruction occured."),
+                UNDEF => {
+                    self.locked = true;
+                    self.event = Some(CpuEvent::IllegalOpcode {address, opcode});
+                },
+            }
+            // the remaining cycles of the instruction have no memory access
+            while self.cycles < duration {
+                self.internal_cycle();
             }
-            cycles += instr.cycles as isize;
         }
-        let irq = self.mmu.read(0xffff) & self.mmu.read(0xff0f);
-        if irq != 0 {
+        let irq = self.bus.pending_interrupts();
+        if irq != 0 && !self.locked {
             self.hlt = false;
-            if self.ie {
-                self.ie = false;
-                self.mmu.write(0xff0f, 0);
-                let mut rst_target:u8 = 0;
-                if irq & 0x01 != 0 {rst_target = 0x40;}
-                else if irq & 0x02 != 0 {rst_target = 0x48;}
-                else if irq & 0x04 != 0 {rst_target = 0x50;}
-                else if irq & 0x08 != 0 {rst_target = 0x58;}
-                else if irq & 0x10 != 0 {rst_target = 0x60;}
-                self.jump(OpJump::RST, rst_target, Immediate::None);
-                cycles += 16;
+            if self.ime {
+                self.dispatch_interrupt();
             }
         }
-        cycles
+        self.cycles
     }
 }
@@ -1,21 +1,46 @@
 use std::collections::HashSet;
-use std::io;
-use std::io::Write;
+use std::fs;
+use std::path::PathBuf;
 
 use crate::cpu::*;
+use crate::gameboy::GameBoy;
 use crate::ppu::Ppu;
 use crate::instructions;
+use crate::rewind::Rewind;
+use crate::state::{self, StateError};
 
-extern crate image as im;
-use im::{ImageBuffer, Rgba};
 
+// what to do when the cpu locks up on an illegal opcode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IllegalOpcodePolicy {
+    // keep running with the cpu locked up, like the hardware
+    Lock,
+    // stop and wait for a command
+    Break,
+    Panic,
+}
+
+impl std::str::FromStr for IllegalOpcodePolicy {
+    type Err = String;
+
+    fn from_str(word:&str) -> Result<IllegalOpcodePolicy, String> {
+        match word {
+            "lock" => Ok(IllegalOpcodePolicy::Lock),
+            "break" => Ok(IllegalOpcodePolicy::Break),
+            "panic" => Ok(IllegalOpcodePolicy::Panic),
+            _ => Err(format!("expected lock, break or panic: {}", word)),
+        }
+    }
+}
 
 pub struct Debugger {
-    cpu: Cpu,
-    ppu: Ppu,
+    gb: GameBoy,
     breakpoints: HashSet<u16>,
     trace: bool,
     running: bool,
+    rom: PathBuf,
+    rewind: Rewind,
+    illegal_opcode: IllegalOpcodePolicy,
 }
 
 enum DbgCommand {
@@ -27,9 +52,11 @@ enum DbgCommand {
     ToggleTrace,
     Quit,
     DumpMemory (u16),
+    SaveState (u8),
+    LoadState (u8),
 }
 
-fn parse_command(line: &String) -> DbgCommand {
+fn parse_command(line: &str) -> DbgCommand {
     use DbgCommand::*;
     let mut iter = line.split_whitespace();
     match iter.next() {
@@ -58,126 +85,249 @@ fn parse_command(line: &String) -> DbgCommand {
             },
             _ => Error,
         }
+        Some("ss") => match iter.next().map(|word| word.parse()) {
+            Some(Ok(slot)) => SaveState(slot),
+            _ => Error,
+        }
+        Some("ls") => match iter.next().map(|word| word.parse()) {
+            Some(Ok(slot)) => LoadState(slot),
+            _ => Error,
+        }
         _ => Error,
     }
 }
 
 impl Debugger {
-    pub fn new(cpu:Cpu, ppu:Ppu) -> Debugger{
-        Debugger {cpu, ppu, breakpoints: HashSet::new(), trace:true, running: false}
+    pub fn new(gb:GameBoy, rom:&str) -> Debugger{
+        Debugger {
+            gb,
+            breakpoints: HashSet::new(),
+            trace:true,
+            running: false,
+            rom: PathBuf::from(rom),
+            rewind: Rewind::new(10, 32 << 20),
+            illegal_opcode: IllegalOpcodePolicy::Break,
+        }
+    }
+
+    pub fn gameboy(&self) -> &GameBoy {
+        &self.gb
+    }
+
+    pub fn gameboy_mut(&mut self) -> &mut GameBoy {
+        &mut self.gb
+    }
+
+    // true while continuing, false while waiting for a command
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    // the next instruction, registers and ppu state as shown at the prompt
+    pub fn status(&self) -> String {
+        format!("{}  {}  {}", dis_instr(&self.gb.cpu.bus, self.gb.cpu.pc), cpustate(&self.gb.cpu), ppustate(self.gb.ppu(), &self.gb.cpu.bus))
+    }
+
+    // take a snapshot every interval frames, keeping at most budget bytes of them
+    pub fn configure_rewind(&mut self, interval:usize, budget:usize) {
+        self.rewind = Rewind::new(interval, budget);
+    }
+
+    pub fn set_illegal_opcode_policy(&mut self, policy:IllegalOpcodePolicy) {
+        self.illegal_opcode = policy;
+    }
+
+    pub fn rewind_snapshots(&self) -> usize {
+        self.rewind.count()
     }
 
-    pub fn interact(&mut self, lcd: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, max_cycles:isize, buttons:u8) -> isize {
-        self.cpu.mmu.set_buttons(buttons);
-        if self.running {
-            self.run_to_breakpoint(lcd, false, max_cycles)
+    // goes back to the newest snapshot and runs one frame to show it, false if there is none left
+    pub fn rewind(&mut self) -> bool {
+        let snapshot = match self.rewind.pop() {
+            Some(snapshot) => snapshot,
+            None => return false,
+        };
+        if let Err(err) = self.gb.load_state(&snapshot) {
+            println!("could not rewind: {}", err);
+            return false;
         }
-        else {
-            println!("{}  {}  {}", dis_instr(&self.cpu.mmu, self.cpu.pc), cpustate(&self.cpu), ppustate(&self.ppu, &self.cpu.mmu));
-            print!("rboy dbg> ");
-            io::stdout().flush().expect("error on stdout.flush");
-
-            let mut line = String::new();
-            io::stdin().read_line(&mut line).expect("Could not read command from stdin.");
-
-            use DbgCommand::*;
-            match parse_command(&line) {
-                Continue => self.run_to_breakpoint(lcd, false, max_cycles),
-                SingleStep => self.run_to_breakpoint(lcd, true, max_cycles),
-                SetBreakpoint(addr) => {self.breakpoints.insert(addr);0},
-                ClearBreakpoint(addr) => {self.breakpoints.remove(&addr);0},
-                ToggleTrace => {
-                    self.trace = !self.trace;
-                    println!("trace is {}.", if self.trace {"on"} else {"off"});
-                    0
-                },
-                Quit => 0,
-                DumpMemory(addr) => {
-                    let start = if addr < 0xff00 {addr} else {0xff00};
-                    for i in 0..16 {
-                        let md = |a| format!("{:02x}{:02x}{:02x}{:02x}",
-                            self.cpu.mmu.read(a),
-                            self.cpu.mmu.read(a+1),
-                            self.cpu.mmu.read(a+2),
-                            self.cpu.mmu.read(a+3));
-                        let base = start + 16*i;
-                        println!("{:04x}  {} {}  {} {}",
-                            base,
-                            md(base),
-                            md(base+4),
-                            md(base+8),
-                            md(base+12));
-                    }
-                    0
-                },
-                Error => {
-                    println!("DebuggerCommands:\n  c: continue\n  s: single step\n  b addr: set breakpoint\n  cl addr: clear breakpoint");
-                    0
-                },
-            }
+        self.gb.run_frame();
+        true
+    }
+
+    pub fn save_state(&self, slot:u8) -> Result<PathBuf, StateError> {
+        let path = state::slot_path(&self.rom, slot);
+        fs::write(&path, self.gb.save_state())?;
+        Ok(path)
+    }
+
+    pub fn load_state(&mut self, slot:u8) -> Result<PathBuf, StateError> {
+        let path = state::slot_path(&self.rom, slot);
+        let data = fs::read(&path)?;
+        self.gb.load_state(&data)?;
+        Ok(path)
+    }
+
+    // continues up to max_cycles unless a breakpoint is hit, returns the cycles run
+    pub fn run(&mut self, max_cycles:isize) -> isize {
+        self.run_to_breakpoint(false, max_cycles)
+    }
+
+    // executes one debugger command line, returns the cycles run
+    pub fn execute(&mut self, line:&str, max_cycles:isize) -> isize {
+        use DbgCommand::*;
+        match parse_command(line) {
+            Continue => self.run_to_breakpoint(false, max_cycles),
+            SingleStep => self.run_to_breakpoint(true, max_cycles),
+            SetBreakpoint(addr) => {self.breakpoints.insert(addr);0},
+            ClearBreakpoint(addr) => {self.breakpoints.remove(&addr);0},
+            ToggleTrace => {
+                self.trace = !self.trace;
+                println!("trace is {}.", if self.trace {"on"} else {"off"});
+                0
+            },
+            Quit => 0,
+            DumpMemory(addr) => {
+                let bus = &self.gb.cpu.bus;
+                let start = if addr < 0xff00 {addr} else {0xff00};
+                for i in 0..16 {
+                    let md = |a| format!("{:02x}{:02x}{:02x}{:02x}",
+                        bus.peek(a),
+                        bus.peek(a+1),
+                        bus.peek(a+2),
+                        bus.peek(a+3));
+                    let base = start + 16*i;
+                    println!("{:04x}  {} {}  {} {}",
+                        base,
+                        md(base),
+                        md(base+4),
+                        md(base+8),
+                        md(base+12));
+                }
+                0
+            },
+            SaveState(slot) => {
+                match self.save_state(slot) {
+                    Ok(path) => println!("saved state to {}", path.display()),
+                    Err(err) => println!("could not save state: {}", err),
+                }
+                0
+            },
+            LoadState(slot) => {
+                match self.load_state(slot) {
+                    Ok(path) => println!("loaded state from {}", path.display()),
+                    Err(err) => println!("could not load state: {}", err),
+                }
+                0
+            },
+            Error => {
+                println!("DebuggerCommands:\n  c: continue\n  s: single step\n  b addr: set breakpoint\n  cl addr: clear breakpoint\n  ss slot: save state\n  ls slot: load state");
+                0
+            },
         }
     }
 
-    fn run_to_breakpoint(&mut self, lcd: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, single_step: bool, max_cycles:isize) -> isize {
+    fn run_to_breakpoint(&mut self, single_step: bool, max_cycles:isize) -> isize {
         let mut total_cycles = 0;
         let mut max_cycles = max_cycles;
         self.running = true;
         while max_cycles > 0 {
-            let cycles = self.cpu.step();
+            let frame = self.gb.ppu().frames;
+            let cycles = self.gb.step();
             total_cycles += cycles;
             max_cycles -= cycles;
-            self.ppu.run_for(&mut self.cpu.mmu, lcd, cycles);
-            self.cpu.mmu.tick(cycles);
 
-            if single_step | self.breakpoints.contains(&self.cpu.pc) {
+            if self.gb.ppu().frames != frame && self.rewind.frame() {
+                self.rewind.push(self.gb.save_state());
+            }
+
+            if let Some(CpuEvent::IllegalOpcode {address, opcode}) = self.gb.cpu.take_event() {
+                let message = format!("illegal opcode {:02x} at {:04x}, the cpu locked up", opcode, address);
+                match self.illegal_opcode {
+                    IllegalOpcodePolicy::Lock => println!("{}", message),
+                    IllegalOpcodePolicy::Break => {
+                        println!("{}", message);
+                        self.running = false;
+                        break;
+                    },
+                    IllegalOpcodePolicy::Panic => panic!("{}", message),
+                }
+            }
+
+            if single_step | self.breakpoints.contains(&self.gb.cpu.pc) {
                 self.running = false;
                 break;
             }
 
             if self.trace {
-                println!("{}  {}  {}", dis_instr(&self.cpu.mmu, self.cpu.pc), cpustate(&self.cpu), ppustate(&self.ppu, &self.cpu.mmu));
+                println!("{}", self.status());
             }
         }
         total_cycles
     }
 }
 
-fn dis_instr(mmu:&Mmu, addr:u16) -> String {
-    let mut instr = &instructions::INSTRUCTIONS[mmu.read(addr) as usize];
+// the debug reads use peek, they see the bytes even where the cpu is
+// locked out during oam dma or while the ppu is drawing
+fn dis_instr(bus:&Mmu, addr:u16) -> String {
+    let mut instr = &instructions::INSTRUCTIONS[bus.peek(addr) as usize];
     if instr.operation == instructions::Operation::PREFIX {
-        instr = &instructions::INSTRUCTIONS[mmu.read(addr+1) as usize + 0x100];
+        instr = &instructions::INSTRUCTIONS[bus.peek(addr+1) as usize + 0x100];
     }
     let instr = instr;
 
     match instr.length {
         2 => format!("0x{:04x}: {:02x}{:02x}    {:11} 0x{:02x}  ",
-                    addr, mmu.read(addr), mmu.read(addr+1), instr.mnemo, mmu.read(addr+1)),
+                    addr, bus.peek(addr), bus.peek(addr+1), instr.mnemo, bus.peek(addr+1)),
         3 => format!("0x{:04x}: {:02x}{:02x}{:02x}  {:11} 0x{:02x}{:02x}",
-                    addr, mmu.read(addr), mmu.read(addr+1), mmu.read(addr+2), instr.mnemo, mmu.read(addr+2), mmu.read(addr+1)),
+                    addr, bus.peek(addr), bus.peek(addr+1), bus.peek(addr+2), instr.mnemo, bus.peek(addr+2), bus.peek(addr+1)),
         _ => format!("0x{:04x}: {:02x}      {:11}       ",
-                    addr, mmu.read(addr), instr.mnemo),
+                    addr, bus.peek(addr), instr.mnemo),
     }
 }
 
 fn cpustate(cpu:&Cpu) -> String {
     format!("A:{:02x} B:{:02x} C:{:02x} D:{:02x} E:{:02x} HL:{:02x}{:02x}->{:02x} SP:{:04x}->{:02x} {}{}{}{}{} IF:{:02x} IE:{:02x}  ",
               cpu.a, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l,
-              cpu.mmu.read(word(cpu.h, cpu.l)), cpu.sp, cpu.mmu.read(cpu.sp),
+              cpu.bus.peek(word(cpu.h, cpu.l)), cpu.sp, cpu.bus.peek(cpu.sp),
               if FLAG_Z & cpu.f != 0 {"Z"} else {"-"},
               if FLAG_N & cpu.f != 0 {"N"} else {"-"},
               if FLAG_H & cpu.f != 0 {"H"} else {"-"},
               if FLAG_C & cpu.f != 0 {"C"} else {"-"},
-              if cpu.ie {"I"} else {"-"},
-              cpu.mmu.read(0xff0f),
-              cpu.mmu.read(0xffff),
+              if cpu.ime {"I"} else {"-"},
+              cpu.bus.peek(0xff0f),
+              cpu.bus.peek(0xffff),
           )
 }
 
-fn ppustate(ppu:&Ppu, mmu:&Mmu) -> String {
+fn ppustate(ppu:&Ppu, bus:&Mmu) -> String {
     format!("  x={} y={} mode={} cycles_left={}",
         ppu.x,
-        mmu.read(0xff44),
+        bus.peek(0xff44),
         ppu.mode,
         ppu.cycles_left,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_during_dma() {
+        let mut gb = GameBoy::new(Mmu::new());
+        // LD A,0x42 in work ram, with HL pointing at the 0x42
+        gb.cpu.bus.write(0xc000, 0x3e);
+        gb.cpu.bus.write(0xc001, 0x42);
+        gb.cpu.pc = 0xc000;
+        gb.cpu.h = 0xc0;
+        gb.cpu.l = 0x01;
+        gb.cpu.bus.write(0xff46, 0xc0);
+        gb.cpu.bus.tick(8);
+        assert_eq!(gb.cpu.bus.read(0xc000), 0xff);
+
+        let status = Debugger::new(gb, "test.gb").status();
+        assert!(status.starts_with("0xc000: 3e42"), "{}", status);
+        assert!(status.contains("HL:c001->42"), "{}", status);
+    }
+}
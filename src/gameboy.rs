@@ -0,0 +1,137 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use crate::cartridge::{CartridgeError, CartridgeHeader};
+use crate::cpu::{Cpu, CpuEvent, Mmu};
+use crate::ppu::{Ppu, CYCLES_PER_FRAME};
+use crate::state::{self, StateError};
+
+extern crate image as im;
+use im::{ImageBuffer, Rgba};
+
+#[derive(Debug)]
+pub enum LoadError {
+    Cartridge(CartridgeError),
+    // the path and what went wrong
+    BootRom(String, io::Error),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Cartridge(err) => write!(f, "{}", err),
+            LoadError::BootRom(path, err) => write!(f, "could not load boot rom {}: {}", path, err),
+        }
+    }
+}
+
+impl error::Error for LoadError {}
+
+impl From<CartridgeError> for LoadError {
+    fn from(err:CartridgeError) -> LoadError {
+        LoadError::Cartridge(err)
+    }
+}
+
+// The whole machine without any frontend: feed it buttons, run it frame by
+// frame and read back the picture.
+pub struct GameBoy {
+    pub cpu: Cpu,
+}
+
+impl GameBoy {
+    pub fn new(mmu:Mmu) -> GameBoy {
+        GameBoy {cpu: Cpu::new(mmu)}
+    }
+
+    pub fn load(rom:&str, boot_rom:&str) -> Result<(GameBoy, CartridgeHeader), LoadError> {
+        let mut mmu = Mmu::new();
+        let header = mmu.load(rom)?;
+        mmu.load_boot_rom(boot_rom).map_err(|err| LoadError::BootRom(boot_rom.to_string(), err))?;
+        Ok((GameBoy::new(mmu), header))
+    }
+
+    // executes one instruction, the rest of the hardware runs along, returns the cycles taken
+    pub fn step(&mut self) -> isize {
+        self.cpu.step()
+    }
+
+    pub fn ppu(&self) -> &Ppu {
+        self.cpu.bus.ppu()
+    }
+
+    // runs until the ppu finished a frame, returns the cycles taken
+    pub fn run_frame(&mut self) -> isize {
+        let frame = self.ppu().frames;
+        let mut cycles = 0;
+        // the limit keeps a stopped display from hanging the caller
+        while self.ppu().frames == frame && cycles < 2 * CYCLES_PER_FRAME {
+            cycles += self.step();
+        }
+        cycles
+    }
+
+    // runs until the cpu reports an event, gives up after max_frames
+    pub fn run_until_event(&mut self, max_frames:u64) -> Option<CpuEvent> {
+        for _ in 0..max_frames {
+            let frame = self.ppu().frames;
+            let mut cycles = 0;
+            while self.ppu().frames == frame && cycles < 2 * CYCLES_PER_FRAME {
+                cycles += self.step();
+                if let Some(event) = self.cpu.take_event() {
+                    return Some(event);
+                }
+            }
+        }
+        None
+    }
+
+    // at their software breakpoint mooneye test roms leave the fibonacci
+    // numbers 3, 5, 8, 13, 21, 34 in B, C, D, E, H, L when they passed
+    pub fn mooneye_passed(&self) -> bool {
+        let cpu = &self.cpu;
+        [cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l] == [3, 5, 8, 13, 21, 34]
+    }
+
+    // bit set = pressed: Right, Left, Up, Down, A, B, Select, Start from bit 0 to 7
+    pub fn set_buttons(&mut self, buttons:u8) {
+        self.cpu.bus.set_buttons(buttons);
+    }
+
+    // 160x144 pixels, 4 bytes RGBA each
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.ppu().lcd
+    }
+
+    // 160x144 colour numbers 0 - 3 before BGP, OBP0 and OBP1 were applied
+    pub fn color_indices(&self) -> &[u8] {
+        &self.ppu().colors
+    }
+
+    pub fn lcd(&self) -> &ImageBuffer<Rgba<u8>, Vec<u8>> {
+        &self.ppu().lcd
+    }
+
+    // the bytes sent over the link port, test roms report their results there
+    pub fn serial_output(&self) -> &[u8] {
+        self.cpu.bus.serial().output()
+    }
+
+    // there is no sound emulation yet, so this is always empty
+    pub fn audio_samples(&self) -> &[i16] {
+        &[]
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        state::save_state(&self.cpu)
+    }
+
+    pub fn load_state(&mut self, data:&[u8]) -> Result<(), StateError> {
+        state::load_state(&mut self.cpu, data)
+    }
+
+    pub fn save_ram(&mut self) -> io::Result<()> {
+        self.cpu.bus.save_ram()
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::process;
+
+use rustyboy::GameBoy;
+use rustyboy::cpu::CpuEvent;
+
+const USAGE:&str = "usage: rboy-headless [options] game.rom
+  --boot file        boot rom, default RBOY_ROM.bin
+  --frames n         stop after n frames
+  --cycles n         stop after n cycles
+  --pc addr          stop when pc reaches addr (hex), can be given more than once
+  --ldbb             stop at the software breakpoint LD B,B
+  --input file       scripted buttons, lines of: frame button,button,...
+  --png file         write the final screen
+  --dump file        write the 64k address space
+  --regs file        write the registers
+  --pass-if addr=v   exit 0 if memory at addr is v (hex), else 1
+  --pass-if fib      exit 0 if B,C,D,E,H,L hold 3,5,8,13,21,34 (mooneye), else 1
+without --frames and --cycles the run stops after 3600 frames";
+
+const DEFAULT_FRAMES:u64 = 3600;
+
+// exit codes
+const PASS:i32 = 0;
+const FAIL:i32 = 1;
+const ERROR:i32 = 2;
+
+enum PassIf {
+    Memory(u16, u8),
+    Fibonacci,
+}
+
+struct Options {
+    rom: String,
+    boot_rom: String,
+    frames: Option<u64>,
+    cycles: Option<u64>,
+    pcs: HashSet<u16>,
+    ldbb: bool,
+    input: Vec<(u64, u8)>,
+    png: Option<String>,
+    dump: Option<String>,
+    regs: Option<String>,
+    pass_if: Option<PassIf>,
+}
+
+fn parse_hex(word:&str) -> Result<u16, String> {
+    u16::from_str_radix(word.trim_start_matches("0x"), 16).map_err(|_| format!("not a hex number: {}", word))
+}
+
+// bit set = pressed, in the order of GameBoy::set_buttons
+fn parse_buttons(word:&str) -> Result<u8, String> {
+    let mut buttons = 0;
+    for name in word.split(',') {
+        buttons |= match name {
+            "right" => 0x01,
+            "left" => 0x02,
+            "up" => 0x04,
+            "down" => 0x08,
+            "a" => 0x10,
+            "b" => 0x20,
+            "select" => 0x40,
+            "start" => 0x80,
+            "none" => 0x00,
+            _ => return Err(format!("unknown button: {}", name)),
+        };
+    }
+    Ok(buttons)
+}
+
+// each line holds the buttons from the given frame on until the next line, # starts a comment
+fn parse_input(script:&str) -> Result<Vec<(u64, u8)>, String> {
+    let mut input = Vec::new();
+    for line in script.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut words = line.split_whitespace();
+        let frame = match words.next() {
+            Some(word) => word.parse().map_err(|_| format!("not a frame number: {}", word))?,
+            None => continue,
+        };
+        let buttons = parse_buttons(words.next().unwrap_or("none"))?;
+        input.push((frame, buttons));
+    }
+    input.sort_by_key(|&(frame, _)| frame);
+    Ok(input)
+}
+
+fn parse_pass_if(word:&str) -> Result<PassIf, String> {
+    if word == "fib" {
+        return Ok(PassIf::Fibonacci);
+    }
+    let mut parts = word.split('=');
+    match (parts.next(), parts.next()) {
+        (Some(addr), Some(value)) => Ok(PassIf::Memory(parse_hex(addr)?, parse_hex(value)? as u8)),
+        _ => Err(format!("expected addr=value or fib: {}", word)),
+    }
+}
+
+fn parse_args(args:&[String]) -> Result<Options, String> {
+    let mut options = Options {
+        rom: String::new(),
+        boot_rom: String::from("RBOY_ROM.bin"),
+        frames: None,
+        cycles: None,
+        pcs: HashSet::new(),
+        ldbb: false,
+        input: Vec::new(),
+        png: None,
+        dump: None,
+        regs: None,
+        pass_if: None,
+    };
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if !arg.starts_with("--") {
+            options.rom = arg.clone();
+            continue;
+        }
+        if arg == "--ldbb" {
+            options.ldbb = true;
+            continue;
+        }
+        let value = iter.next().ok_or(format!("{} needs a value", arg))?;
+        match arg.as_str() {
+            "--boot" => options.boot_rom = value.clone(),
+            "--frames" => options.frames = Some(value.parse().map_err(|_| format!("not a number: {}", value))?),
+            "--cycles" => options.cycles = Some(value.parse().map_err(|_| format!("not a number: {}", value))?),
+            "--pc" => {options.pcs.insert(parse_hex(value)?);},
+            "--input" => {
+                let script = fs::read_to_string(value).map_err(|err| format!("{}: {}", value, err))?;
+                options.input = parse_input(&script)?;
+            },
+            "--png" => options.png = Some(value.clone()),
+            "--dump" => options.dump = Some(value.clone()),
+            "--regs" => options.regs = Some(value.clone()),
+            "--pass-if" => options.pass_if = Some(parse_pass_if(value)?),
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+    if options.rom.is_empty() {
+        return Err(String::from("no rom given"));
+    }
+    if options.frames.is_none() && options.cycles.is_none() {
+        options.frames = Some(DEFAULT_FRAMES);
+    }
+    Ok(options)
+}
+
+// runs until one of the stop conditions is met, returns why it stopped
+fn run(gb:&mut GameBoy, options:&Options) -> String {
+    let mut cycles = 0u64;
+    let mut input = options.input.iter().peekable();
+    loop {
+        let frames = gb.ppu().frames;
+        while let Some(&&(frame, buttons)) = input.peek() {
+            if frame > frames {
+                break;
+            }
+            gb.set_buttons(buttons);
+            input.next();
+        }
+
+        if options.frames.is_some_and(|n| frames >= n) {
+            return format!("ran {} frames", frames);
+        }
+        if options.cycles.is_some_and(|n| cycles >= n) {
+            return format!("ran {} cycles", cycles);
+        }
+        cycles += gb.step() as u64;
+        match gb.cpu.take_event() {
+            Some(CpuEvent::SoftwareBreakpoint) if options.ldbb => {
+                return String::from("reached the software breakpoint LD B,B");
+            },
+            // nothing happens anymore after that
+            Some(CpuEvent::IllegalOpcode {address, opcode}) => {
+                return format!("illegal opcode {:02x} at {:04x}, the cpu locked up", opcode, address);
+            },
+            _ => (),
+        }
+        if options.pcs.contains(&gb.cpu.pc) {
+            return format!("reached pc {:04x}", gb.cpu.pc);
+        }
+    }
+}
+
+fn registers(gb:&GameBoy) -> String {
+    let cpu = &gb.cpu;
+    format!("A:{:02x} F:{:02x} B:{:02x} C:{:02x} D:{:02x} E:{:02x} H:{:02x} L:{:02x} SP:{:04x} PC:{:04x} IME:{} HALT:{} IF:{:02x} IE:{:02x} frames:{}\n",
+        cpu.a, cpu.f, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l, cpu.sp, cpu.pc,
+        cpu.ime as u8, cpu.hlt as u8, cpu.bus.read(0xff0f), cpu.bus.read(0xffff), gb.ppu().frames)
+}
+
+fn passed(gb:&GameBoy, pass_if:&PassIf) -> bool {
+    match *pass_if {
+        PassIf::Memory(addr, value) => gb.cpu.bus.peek(addr) == value,
+        PassIf::Fibonacci => gb.mooneye_passed(),
+    }
+}
+
+fn write_outputs(gb:&GameBoy, options:&Options) -> Result<(), String> {
+    if let Some(path) = &options.png {
+        gb.lcd().save(path).map_err(|err| format!("{}: {}", path, err))?;
+    }
+    if let Some(path) = &options.dump {
+        let memory:Vec<u8> = (0..=0xffff).map(|addr| gb.cpu.bus.peek(addr)).collect();
+        fs::write(path, memory).map_err(|err| format!("{}: {}", path, err))?;
+    }
+    if let Some(path) = &options.regs {
+        fs::write(path, registers(gb)).map_err(|err| format!("{}: {}", path, err))?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}\n{}", err, USAGE);
+            process::exit(ERROR);
+        }
+    };
+
+    let mut gb = match GameBoy::load(&options.rom, &options.boot_rom) {
+        Ok((gb, header)) => {
+            if let Some(warning) = header.size_warning() {
+                eprintln!("warning: {}", warning);
+            }
+            gb
+        },
+        Err(err) => {
+            eprintln!("{}: {}", options.rom, err);
+            process::exit(ERROR);
+        }
+    };
+
+    let reason = run(&mut gb, &options);
+    println!("{}", reason);
+    print!("{}", registers(&gb));
+
+    if let Err(err) = write_outputs(&gb, &options) {
+        eprintln!("{}", err);
+        process::exit(ERROR);
+    }
+
+    match &options.pass_if {
+        Some(pass_if) if passed(&gb, pass_if) => {
+            println!("pass");
+            process::exit(PASS);
+        },
+        Some(_) => {
+            println!("fail");
+            process::exit(FAIL);
+        },
+        None => process::exit(PASS),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_input() {
+        let input = parse_input("# press start\n120 start\n60 a,right\n\n130 none  # release\n").unwrap();
+        assert_eq!(input, vec![(60, 0x11), (120, 0x80), (130, 0x00)]);
+        assert!(parse_input("10 jump").is_err());
+    }
+}
@@ -0,0 +1,11 @@
+pub mod bus;
+pub mod cartridge;
+pub mod cpu;
+pub mod debugger;
+pub mod gameboy;
+pub mod instructions;
+pub mod ppu;
+pub mod rewind;
+pub mod state;
+
+pub use gameboy::GameBoy;
@@ -1,20 +1,59 @@
 use std::env;
-mod cpu;
-mod ppu;
-mod debugger;
-mod instructions;
-use ppu::{LCD_WIDTH, LCD_HEIGHT, Ppu};
-use cpu::{Cpu, Mmu};
-use debugger::Debugger;
-
-extern crate image as im;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::process;
+use rustyboy::ppu::{LCD_WIDTH, LCD_HEIGHT};
+use rustyboy::cartridge::{CartridgeError, CartridgeHeader};
+use rustyboy::debugger::{Debugger, IllegalOpcodePolicy};
+use rustyboy::GameBoy;
+
 extern crate piston_window;
 extern crate fps_counter;
 use piston_window::*;
 use piston_window::keyboard::Key;
 use piston_window::Button::Keyboard;
 
-fn main_ppu() {
+const REWIND_INTERVAL:usize = 10;
+const REWIND_BUDGET:usize = 64 << 20;
+
+// runs the emulation while the debugger is running, otherwise reads and executes a command from stdin
+fn interact(dbg: &mut Debugger, max_cycles: isize, buttons: u8) -> isize {
+    dbg.gameboy_mut().set_buttons(buttons);
+    if dbg.is_running() {
+        dbg.run(max_cycles)
+    }
+    else {
+        println!("{}", dbg.status());
+        print!("rboy dbg> ");
+        io::stdout().flush().expect("error on stdout.flush");
+
+        let mut line = String::new();
+        io::stdin().read_line(&mut line).expect("Could not read command from stdin.");
+        dbg.execute(&line, max_cycles)
+    }
+}
+
+fn main_ppu(rom: &str, boot_rom: &str, illegal_opcode: IllegalOpcodePolicy) {
+    let mut gb = match GameBoy::load(rom, boot_rom) {
+        Ok((gb, header)) => {
+            println!("{}\n", header);
+            if let Some(warning) = header.size_warning() {
+                eprintln!("warning: {}", warning);
+            }
+            gb
+        },
+        Err(err) => {
+            eprintln!("{}: {}", rom, err);
+            process::exit(1);
+        }
+    };
+    gb.cpu.bus.set_rumble_callback(Box::new(|on| println!("rumble {}", if on {"on"} else {"off"})));
+
+    let mut dbg = Debugger::new(gb, rom);
+    dbg.configure_rewind(REWIND_INTERVAL, REWIND_BUDGET);
+    dbg.set_illegal_opcode_policy(illegal_opcode);
+
     const ZOOM:u32 = 3;
     let opengl = OpenGL::V3_2;
     let mut window: PistonWindow =
@@ -24,14 +63,13 @@ fn main_ppu() {
         .build()
         .unwrap();
 
-    let mut lcd = im::ImageBuffer::from_pixel(LCD_WIDTH as u32, LCD_HEIGHT as u32, im::Rgba([0u8;4]));
     let mut texture_context = TextureContext {
         factory: window.factory.clone(),
         encoder: window.factory.create_command_buffer().into()
     };
     let mut texture: G2dTexture = Texture::from_image(
             &mut texture_context,
-            &lcd,
+            dbg.gameboy().lcd(),
             &{let mut ts = TextureSettings::new(); ts.set_mag(Filter::Nearest); ts},
         ).unwrap();
 
@@ -43,31 +81,36 @@ fn main_ppu() {
     let mut ups_ctr = fps_counter::FPSCounter::new();
     let mut ups = 0usize;
 
-    let mut mmu = Mmu::new();
-    {
-        let args: Vec<String> = env::args().collect();
-        mmu.load(&args[1], 0);
-        if args.len() > 2 {
-            mmu.load_boot_rom(&args[2]);
-        } else {
-            mmu.load_boot_rom("RBOY_ROM.bin");
-        }
-    }
-
-    let cpu = Cpu::new(mmu);
-    let ppu = Ppu::new();
-    let mut dbg = Debugger::new(cpu, ppu);
-
     let ups_target = 120;
     let cycles_per_second = 4*1024*1024;
     let cycles_per_update = cycles_per_second / ups_target;
 
     let mut buttons:u8 = 0;
+    let mut shift = false;
+    let mut rewinding = false;
+    let mut rewind_updates = 0isize;
+    let mut updates = 0isize;
 
     while let Some(e) = window.next() {
         if let Some(_) = e.update_args() {
-            dbg.interact(&mut lcd, cycles_per_update, buttons);
+            if rewinding {
+                // one snapshot per REWIND_INTERVAL frames, at two updates per frame
+                if rewind_updates % (2 * REWIND_INTERVAL as isize) == 0 && !dbg.rewind() {
+                    println!("rewind buffer is empty");
+                    rewinding = false;
+                }
+                rewind_updates += 1;
+            } else {
+                interact(&mut dbg, cycles_per_update, buttons);
+            }
             ups = ups_ctr.tick();
+            // flush save ram once per second so a crash loses little progress
+            updates += 1;
+            if updates % ups_target == 0 {
+                if let Err(err) = dbg.gameboy_mut().save_ram() {
+                    eprintln!("could not write save file: {}", err);
+                }
+            }
         }
         if let Some(_) = e.render_args() {
             fps_print_ctr += 1;
@@ -76,9 +119,49 @@ fn main_ppu() {
                 println!("fps = {}   ups = {}", fps, ups);
                 fps_print_ctr = 0;
             }
-            texture.update(&mut texture_context, &lcd).unwrap();
+            texture.update(&mut texture_context, dbg.gameboy().lcd()).unwrap();
         }
         if let Some(args) = e.button_args() {
+            // F1-F10 save the state to slots 1-10, together with shift they load it
+            let slot = match args.button {
+                Keyboard(Key::LShift) | Keyboard(Key::RShift) => {
+                    shift = args.state == ButtonState::Press;
+                    None
+                },
+                Keyboard(Key::Backspace) => {
+                    let pressed = args.state == ButtonState::Press;
+                    if pressed && !rewinding {
+                        println!("rewinding, {} snapshots available", dbg.rewind_snapshots());
+                        rewind_updates = 0;
+                    }
+                    rewinding = pressed;
+                    None
+                },
+                Keyboard(Key::F1) => Some(1),
+                Keyboard(Key::F2) => Some(2),
+                Keyboard(Key::F3) => Some(3),
+                Keyboard(Key::F4) => Some(4),
+                Keyboard(Key::F5) => Some(5),
+                Keyboard(Key::F6) => Some(6),
+                Keyboard(Key::F7) => Some(7),
+                Keyboard(Key::F8) => Some(8),
+                Keyboard(Key::F9) => Some(9),
+                Keyboard(Key::F10) => Some(10),
+                _ => None,
+            };
+            if let (Some(slot), ButtonState::Press) = (slot, args.state) {
+                if shift {
+                    match dbg.load_state(slot) {
+                        Ok(path) => println!("loaded state from {}", path.display()),
+                        Err(err) => eprintln!("could not load state: {}", err),
+                    }
+                } else {
+                    match dbg.save_state(slot) {
+                        Ok(path) => println!("saved state to {}", path.display()),
+                        Err(err) => eprintln!("could not save state: {}", err),
+                    }
+                }
+            }
             let key = match args.button {
                 Keyboard(Key::Right) => 0x01,
                 Keyboard(Key::Left) => 0x02,
@@ -105,9 +188,59 @@ fn main_ppu() {
             image(&texture, c.transform.zoom(ZOOM as f64), g);
         });
     }
+
+    if let Err(err) = dbg.gameboy_mut().save_ram() {
+        eprintln!("could not write save file: {}", err);
+    }
+}
+
+fn print_info(rom: &str) {
+    let header = fs::read(rom)
+        .map_err(CartridgeError::from)
+        .and_then(|data| CartridgeHeader::parse(&data));
+    match header {
+        Ok(header) => {
+            println!("{}", header);
+            if let Err(err) = header.mapper() {
+                println!("{}", err);
+            }
+        },
+        Err(err) => {
+            eprintln!("{}: {}", rom, err);
+            process::exit(1);
+        }
+    }
 }
 
 fn main(){
-    main_ppu();
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let info = args.iter().any(|arg| arg == "--info");
+    args.retain(|arg| arg != "--info");
+    let mut illegal_opcode = IllegalOpcodePolicy::Break;
+    if let Some(index) = args.iter().position(|arg| arg == "--illegal") {
+        let policy = args.get(index + 1).map(|word| word.parse());
+        illegal_opcode = match policy {
+            Some(Ok(policy)) => policy,
+            Some(Err(err)) => {
+                eprintln!("{}", err);
+                process::exit(2);
+            },
+            None => {
+                eprintln!("--illegal needs lock, break or panic");
+                process::exit(2);
+            },
+        };
+        args.drain(index..index + 2);
+    }
+    if args.is_empty() {
+        eprintln!("usage: rboy [--info] [--illegal lock|break|panic] game.rom [boot.rom]");
+        process::exit(2);
+    }
+
+    if info {
+        print_info(&args[0]);
+    } else {
+        main_ppu(&args[0], args.get(1).map(String::as_str).unwrap_or("RBOY_ROM.bin"), illegal_opcode);
+    }
     //debugger::main();
 }
@@ -1,10 +1,11 @@
 extern crate image as im;
 use im::{ImageBuffer, Rgba};
 
-use crate::cpu::{Mmu};
+use crate::state::{StateError, StateReader, StateWriter};
 
 pub const LCD_WIDTH:usize = 160;
 pub const LCD_HEIGHT:usize = 144;
+pub const CYCLES_PER_FRAME:isize = 456 * 154;
 
 const LCD_PALETTE:[im::Rgba<u8>;4] = [
     im::Rgba([198,227,195,255]),
@@ -13,7 +14,6 @@ const LCD_PALETTE:[im::Rgba<u8>;4] = [
     im::Rgba([53,61,52,255]),
 ];
 
-#[allow(unused)]
 mod oam_flags {
     pub const PRIORITY:u8 = 0x80;
     pub const FLIP_Y:u8 = 0x40;
@@ -21,7 +21,6 @@ mod oam_flags {
     pub const PALETTE1:u8 = 0x10;
 }
 
-#[allow(unused)]
 mod ctrl_flags {
     pub const DISPLAY_ENABLE:u8 = 0x80;
     pub const WINDOW_TMA:u8 = 0x40;
@@ -43,11 +42,131 @@ mod stat_flags {
     pub const MODE_MASK:u8 = 0x03;
 }
 
+// a pixel waiting in one of the fifos: the 2 bit colour number and, for
+// objects, the oam attribute flags
+#[derive(Clone, Copy, Default)]
+struct Pixel {
+    color: u8,
+    flags: u8,
+}
+
+// holds up to 8 pixels, the next one to shift out first
+#[derive(Clone, Copy, Default)]
+struct Fifo {
+    pixels: [Pixel;8],
+    len: usize,
+}
+
+impl Fifo {
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn push(&mut self, pixel:Pixel) {
+        self.pixels[self.len] = pixel;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<Pixel> {
+        if self.len == 0 {
+            return None;
+        }
+        let pixel = self.pixels[0];
+        self.pixels.copy_within(1.., 0);
+        self.len -= 1;
+        Some(pixel)
+    }
+
+    fn save_state(&self, state:&mut StateWriter) {
+        state.u8(self.len as u8);
+        for pixel in &self.pixels {
+            state.u8(pixel.color);
+            state.u8(pixel.flags);
+        }
+    }
+
+    fn load_state(&mut self, state:&mut StateReader) -> Result<(), StateError> {
+        self.len = (state.u8()? as usize).min(8);
+        for pixel in &mut self.pixels {
+            pixel.color = state.u8()?;
+            pixel.flags = state.u8()?;
+        }
+        Ok(())
+    }
+}
+
+// Fetches a row of 8 background pixels: tile number, low and high byte of
+// the tile data, 2 dots each, then waits until the fifo is empty to push
+// them. The registers are read when needed, so writes in the middle of a
+// line take effect from the next fetch on.
+#[derive(Clone, Copy, Default)]
+struct Fetcher {
+    step: u8,
+    // tiles fetched on this line
+    column: u8,
+    tile: u8,
+    low: u8,
+    high: u8,
+    // the first fetch of a line is thrown away
+    first: bool,
+    // fetching the window instead of the background
+    window: bool,
+}
+
 pub struct Ppu {
     pub cycles_left: isize,
     pub x: u8,
+    // 0 hblank, 1 vblank, 2 oam search, 3 drawing
     pub mode: u8,
-    pub cycles_left_current_line: isize,
+    // dots since the start of the line, 456 per line
+    pub dot: isize,
+    // number of frames completed, counted at the start of vblank
+    pub frames: u64,
+    pub lcd: ImageBuffer<Rgba<u8>, Vec<u8>>,
+    // the colour numbers of the lcd pixels before the palettes, for debugging
+    pub colors: Vec<u8>,
+    vram: [u8;0x2000],
+    oam: [u8;0xa0],
+    lcdc: u8,
+    stat: u8,
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+    fetcher: Fetcher,
+    bg_fifo: Fifo,
+    obj_fifo: Fifo,
+    // pixels still to drop for the fine scroll of SCX
+    discard: u8,
+    // oam indices of the objects on this line not yet fetched
+    line_objs: Vec<u8>,
+    // dots spent fetching the current object
+    obj_fetch: u8,
+    // the window has its own line counter, which only counts lines it was drawn on
+    window_line: u8,
+    // LY matched WY during this frame, the window can show up from then on
+    window_y: bool,
+    // the stat interrupt line, the sources enabled in STAT or-ed together
+    stat_line: bool,
+    // the first line after the lcd is switched on has no oam search
+    first_line: bool,
+    // dots the cpu spent in STOP, where nothing is drawn
+    stopped_dots: isize,
+}
+
+impl Default for Ppu {
+    fn default() -> Ppu {
+        Ppu::new()
+    }
 }
 
 impl Ppu {
@@ -56,122 +175,463 @@ impl Ppu {
             cycles_left: 0,
             x: 0,
             mode: 0,
-            cycles_left_current_line: 0,
+            dot: 0,
+            frames: 0,
+            stopped_dots: 0,
+            lcd: ImageBuffer::from_pixel(LCD_WIDTH as u32, LCD_HEIGHT as u32, LCD_PALETTE[0]),
+            colors: vec![0; LCD_WIDTH * LCD_HEIGHT],
+            vram: [0;0x2000],
+            oam: [0;0xa0],
+            lcdc: 0,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0,
+            obp0: 0,
+            obp1: 0,
+            wy: 0,
+            wx: 0,
+            fetcher: Fetcher::default(),
+            bg_fifo: Fifo::default(),
+            obj_fifo: Fifo::default(),
+            discard: 0,
+            line_objs: Vec::new(),
+            obj_fetch: 0,
+            window_line: 0,
+            window_y: false,
+            stat_line: false,
+            first_line: false,
         }
     }
 
-    pub fn run_for(&mut self, mmu: &mut Mmu, lcd: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, cycles:isize) {
-        self.cycles_left += cycles;
+    // the cpu cannot reach vram while it is drawn
+    pub fn vram_accessible(&self) -> bool {
+        self.mode != 3
+    }
+
+    // nor oam during the oam search and drawing
+    pub fn oam_accessible(&self) -> bool {
+        self.mode < 2
+    }
 
-        let scroll_y = mmu.read(0xff42);
-        let scroll_x = mmu.read(0xff43);
-        let mut ly = mmu.read(0xff44);
+    // vram, oam and the lcd registers 0xff40 - 0xff4b except dma
+    pub fn read(&self, address:u16) -> u8 {
+        match address {
+            0x8000..=0x9fff => self.vram[address as usize - 0x8000],
+            0xfe00..=0xfe9f => self.oam[address as usize - 0xfe00],
+            0xff40 => self.lcdc,
+            0xff41 => 0x80 | (self.stat & 0x78) | if self.ly == self.lyc {stat_flags::LY_FLAG} else {0} | self.mode,
+            0xff42 => self.scy,
+            0xff43 => self.scx,
+            0xff44 => self.ly,
+            0xff45 => self.lyc,
+            0xff47 => self.bgp,
+            0xff48 => self.obp0,
+            0xff49 => self.obp1,
+            0xff4a => self.wy,
+            0xff4b => self.wx,
+            _ => 0xff,
+        }
+    }
+
+    pub fn write(&mut self, address:u16, value:u8) {
+        match address {
+            0x8000..=0x9fff => {self.vram[address as usize - 0x8000] = value;},
+            0xfe00..=0xfe9f => {self.oam[address as usize - 0xfe00] = value;},
+            0xff40 => {
+                let was_on = self.lcdc & ctrl_flags::DISPLAY_ENABLE != 0;
+                self.lcdc = value;
+                match (was_on, value & ctrl_flags::DISPLAY_ENABLE != 0) {
+                    (true, false) => self.switch_off(),
+                    (false, true) => self.switch_on(),
+                    _ => (),
+                }
+            },
+            0xff41 => {self.stat = value & 0x78;},
+            0xff42 => {self.scy = value;},
+            0xff43 => {self.scx = value;},
+            0xff45 => {self.lyc = value;},
+            0xff47 => {self.bgp = value;},
+            0xff48 => {self.obp0 = value;},
+            0xff49 => {self.obp1 = value;},
+            0xff4a => {self.wy = value;},
+            0xff4b => {self.wx = value;},
+            _ => (),
+        }
+    }
+
+    pub fn save_state(&self, state:&mut StateWriter) {
+        state.i64(self.cycles_left as i64);
+        state.u8(self.x);
+        state.u8(self.mode);
+        state.i64(self.dot as i64);
+        state.bytes(&self.vram);
+        state.bytes(&self.oam);
+        for &reg in &[self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.bgp, self.obp0, self.obp1, self.wy, self.wx] {
+            state.u8(reg);
+        }
+        let f = &self.fetcher;
+        for &value in &[f.step, f.column, f.tile, f.low, f.high] {
+            state.u8(value);
+        }
+        state.bool(f.first);
+        state.bool(f.window);
+        self.bg_fifo.save_state(state);
+        self.obj_fifo.save_state(state);
+        state.u8(self.discard);
+        state.bytes(&self.line_objs);
+        state.u8(self.obj_fetch);
+        state.u8(self.window_line);
+        state.bool(self.window_y);
+        state.bool(self.stat_line);
+        state.bool(self.first_line);
+    }
 
-        let control = mmu.read(0xff40);
-        let bgw_tiles = if control & ctrl_flags::BGW_TILE_DATA != 0 {0x8000} else {0x8800};
-        let bg_map = if control & ctrl_flags::BG_TMA == 0 {0x9800} else {0x9c00};
+    pub fn load_state(&mut self, state:&mut StateReader) -> Result<(), StateError> {
+        self.cycles_left = state.i64()? as isize;
+        self.x = state.u8()?;
+        self.mode = state.u8()?;
+        self.dot = state.i64()? as isize;
+        state.bytes_into(&mut self.vram)?;
+        state.bytes_into(&mut self.oam)?;
+        for reg in &mut [&mut self.lcdc, &mut self.stat, &mut self.scy, &mut self.scx, &mut self.ly, &mut self.lyc,
+                         &mut self.bgp, &mut self.obp0, &mut self.obp1, &mut self.wy, &mut self.wx] {
+            **reg = state.u8()?;
+        }
+        let f = &mut self.fetcher;
+        for value in &mut [&mut f.step, &mut f.column, &mut f.tile, &mut f.low, &mut f.high] {
+            **value = state.u8()?;
+        }
+        f.first = state.bool()?;
+        f.window = state.bool()?;
+        self.bg_fifo.load_state(state)?;
+        self.obj_fifo.load_state(state)?;
+        self.discard = state.u8()?;
+        self.line_objs = state.bytes()?.to_vec();
+        self.obj_fetch = state.u8()?;
+        self.window_line = state.u8()?;
+        self.window_y = state.bool()?;
+        self.stat_line = state.bool()?;
+        self.first_line = state.bool()?;
+        Ok(())
+    }
 
+    // runs for the given cycles, returns the interrupts to flag in IF
+    pub fn tick(&mut self, cycles:isize) -> u8 {
+        let mut interrupts = 0;
+        self.cycles_left += cycles;
         while self.cycles_left > 0 {
+            self.cycles_left -= 1;
+            interrupts |= self.run_dot();
+        }
+        interrupts
+    }
+
+    // The cpu is in STOP and the ppu stands still. Frames are counted like
+    // with the lcd off, so whoever runs frame by frame keeps the pace.
+    pub fn idle(&mut self, cycles:isize) {
+        self.stopped_dots += cycles;
+        while self.stopped_dots >= CYCLES_PER_FRAME {
+            self.stopped_dots -= CYCLES_PER_FRAME;
+            self.frames += 1;
+        }
+    }
+
+    // LY and the mode stay 0 while the lcd is off, which shows white
+    fn switch_off(&mut self) {
+        self.ly = 0;
+        self.mode = 0;
+        self.dot = 0;
+        self.stat_line = false;
+        for pixel in self.lcd.pixels_mut() {
+            *pixel = LCD_PALETTE[0];
+        }
+        self.colors.fill(0);
+    }
+
+    // starts over with line 0, which is 4 dots shorter
+    fn switch_on(&mut self) {
+        self.ly = 0;
+        self.mode = 0;
+        self.dot = 4;
+        self.first_line = true;
+        self.line_objs.clear();
+        self.window_line = 0;
+        // there is no oam search to compare LY with WY on this line
+        self.window_y = self.wy == 0;
+    }
+
+    // advances by one dot, returns the interrupts to flag in IF
+    fn run_dot(&mut self) -> u8 {
+        // with the lcd off the frames are still counted, so whoever runs
+        // frame by frame keeps the pace
+        if self.lcdc & ctrl_flags::DISPLAY_ENABLE == 0 {
+            self.dot += 1;
+            if self.dot >= CYCLES_PER_FRAME {
+                self.dot = 0;
+                self.frames += 1;
+            }
+            return 0;
+        }
+        let mut interrupts = 0;
+        match self.mode {
+            // oam search
+            2 if self.dot == 0 => self.oam_search(),
+            // drawing
+            3 => self.draw_dot(),
+            _ => (),
+        }
+        self.dot += 1;
+        if self.dot == 80 && (self.mode == 2 || self.first_line) {
+            self.start_drawing();
+        }
+        if self.dot == 456 {
+            self.dot = 0;
             match self.mode {
                 // vblank: 10 lines
-                0 => {
-                    if self.cycles_left >= 456 {
-                        self.cycles_left -= 456;
-                        if ly >= 153 {
-                            ly = 0;
-                            self.mode = 2;
-                        } else {
-                            ly += 1;
-                        }
-                    } else {
-                        break;
-                    }
+                1 => if self.ly >= 153 {
+                    self.ly = 0;
+                    self.mode = 2;
+                    self.window_line = 0;
+                    self.window_y = false;
+                } else {
+                    self.ly += 1;
                 },
-                // hblank:
-                1 => {
-                    if self.cycles_left >= self.cycles_left_current_line {
-                        self.cycles_left -= self.cycles_left_current_line;
-                        self.cycles_left_current_line = 0;
-                        ly += 1;
-                        if ly < 144 {
-                            self.mode = 2;
-                        } else {
-                            self.mode = 0;
-                            mmu.flag_interrupt(0x01);
-                        }
-                    } else {
-                        break;
+                // hblank
+                _ => {
+                    if self.fetcher.window {
+                        self.window_line += 1;
                     }
-                },
-                // oam_search
-                2 => {
-                    if self.cycles_left >= 80 {
-                        self.cycles_left -= 80;
-                        self.cycles_left_current_line = 456-80;
-                        self.x = 0;
-                        // todo: actually do the oam search
-                        self.mode = 3;
+                    self.ly += 1;
+                    if self.ly < 144 {
+                        self.mode = 2;
                     } else {
-                        break;
+                        self.mode = 1;
+                        self.frames += 1;
+                        interrupts |= 0x01;
                     }
                 },
-                // drawing
-                3 => {
-                    let y_virt = (ly as usize + scroll_y as usize) % 256;
-                    let y_map = (y_virt / 8) as u16;
-                    let y_tile = (y_virt % 8 * 2) as u16;
-
-                    let x_virt = ((self.x as usize + scroll_x as usize) % 256) as u8;
-                    let x_map = (x_virt / 8) as u16;
-                    let x_tile = 7 - x_virt % 8;
-
-                    let tile_no = mmu.read(bg_map + 32*y_map + x_map) as u16;
-
-                    let upper = mmu.read(bgw_tiles + tile_no * 16 + y_tile);
-                    let lower = mmu.read(bgw_tiles + tile_no * 16 + y_tile + 1);
-                    let upper_bit = (upper & (1 << x_tile)) >> x_tile;
-                    let lower_bit = (lower & (1 << x_tile)) >> x_tile;
-
-                    let mut pixel = 2*upper_bit + lower_bit;
-
-                    if control & ctrl_flags::OBJ_ENABLE != 0 {
-                        for obj in 0..0x10 {
-                            let x = mmu.read(0xfe00 + 4*obj + 1);
-                            let y = mmu.read(0xfe00 + 4*obj);
-                            let n = mmu.read(0xfe00 + 4*obj + 2) as u16;
-                            //let flags = mmu.read(0xfe00 + 4*obj + 3);
-                            if (x > self.x) & (x <= self.x + 8) & (y > ly + 8) & (y <= ly + 16) {
-                                let x_tile = x - self.x - 1;
-                                let y_tile = (16 - y + ly) as u16;
-
-                                let upper = mmu.read(0x8000 + n * 16 + y_tile * 2);
-                                let lower = mmu.read(0x8000 + n * 16 + y_tile * 2 + 1);
-                                let upper_bit = (upper & (1 << x_tile)) >> x_tile;
-                                let lower_bit = (lower & (1 << x_tile)) >> x_tile;
-
-                                pixel = 2*upper_bit + lower_bit;
-
-                                break;
-                            }
-                        }
-                    }
+            }
+        }
+        if self.update_stat_line() {
+            interrupts |= 0x02;
+        }
+        interrupts
+    }
 
-                    lcd.put_pixel(self.x as u32, ly as u32, LCD_PALETTE[pixel as usize]);
+    // Returns true on a rising edge of the stat interrupt line. As the
+    // sources share the line, one of them going high while another one
+    // still is does not raise an interrupt.
+    fn update_stat_line(&mut self) -> bool {
+        let line = (self.stat & stat_flags::LY_INTERRUPT != 0 && self.ly == self.lyc)
+            || (self.stat & stat_flags::M0_INTERRUPT != 0 && self.mode == 0)
+            || (self.stat & stat_flags::M1_INTERRUPT != 0 && self.mode == 1)
+            || (self.stat & stat_flags::M2_INTERRUPT != 0 && self.mode == 2);
+        let rising = line && !self.stat_line;
+        self.stat_line = line;
+        rising
+    }
 
-                    self.x += 1;
-                    if self.x >= 160 {
-                        self.mode = 1;
-                    }
-                    self.cycles_left -= 1;
-                    self.cycles_left_current_line -= 1;
+    fn oam_search(&mut self) {
+        if self.ly == self.wy {
+            self.window_y = true;
+        }
+        // the first 10 objects in oam that cover this line, y is the bottom
+        // line of the object plus 16
+        self.line_objs.clear();
+        let ly = self.ly as u16 + 16;
+        let height = self.obj_height() as u16;
+        for obj in 0..40 {
+            let y = self.oam[4*obj] as u16;
+            if ly >= y && ly < y + height {
+                self.line_objs.push(obj as u8);
+                if self.line_objs.len() == 10 {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn obj_height(&self) -> u8 {
+        if self.lcdc & ctrl_flags::OBJ_SIZE != 0 {16} else {8}
+    }
+
+    fn start_drawing(&mut self) {
+        self.mode = 3;
+        self.first_line = false;
+        self.x = 0;
+        self.fetcher = Fetcher {first: true, ..Fetcher::default()};
+        self.bg_fifo.clear();
+        self.obj_fifo.clear();
+        self.discard = self.scx & 7;
+        self.obj_fetch = 0;
+    }
 
+    // Mode 3 takes 172 dots at the least: 160 pixels, 6 dots for the thrown
+    // away first fetch and 6 for the first real one. Fine scrolling and
+    // every object fetched make it longer.
+    fn draw_dot(&mut self) {
+        // Reaching WX - 7 the background fifo is cleared and the fetcher
+        // starts over on the window, which takes 6 dots. With WX below 7
+        // the window starts at the left edge, cut off by 7 - WX pixels.
+        if !self.fetcher.window && self.window_y && self.lcdc & ctrl_flags::WINDOW_ENABLE != 0
+                && self.x as u16 + 7 >= self.wx as u16 {
+            if self.x == 0 {
+                self.discard = 7u8.saturating_sub(self.wx);
+            }
+            self.fetcher = Fetcher {window: true, first: self.fetcher.first, ..Fetcher::default()};
+            self.bg_fifo.clear();
+        }
+        // an object at the current position stops the pixel output until it
+        // is fetched, the background fetch in progress finishes first
+        if self.lcdc & ctrl_flags::OBJ_ENABLE != 0 {
+            let x = self.x as u16 + 8;
+            let oam = &self.oam;
+            // several objects can be due at once when they start left of the
+            // screen, the smallest X goes first and wins the overlap, then
+            // the lowest OAM index which is the order of line_objs
+            let due = self.line_objs.iter().enumerate()
+                .filter(|(_, &obj)| oam[4*obj as usize + 1] as u16 <= x)
+                .min_by_key(|(_, &obj)| oam[4*obj as usize + 1]);
+            if let Some((index, _)) = due {
+                if self.bg_fifo.is_empty() || (1..6).contains(&self.fetcher.step) {
+                    self.fetch_background();
+                } else {
+                    self.obj_fetch += 1;
+                    if self.obj_fetch == 6 {
+                        self.obj_fetch = 0;
+                        let obj = self.line_objs.remove(index);
+                        self.fetch_object(obj as usize);
+                    }
                 }
-                _ => panic!("mode illegal")
+                return;
             }
-        } // wend
+        }
+        self.fetch_background();
+        self.shift_pixel();
+    }
 
-        mmu.write(0xff41, self.mode);
-        mmu.write(0xff44, ly);
+    // address in vram of a row of a background or window tile
+    fn tile_address(&self, tile:u8, row:u8) -> usize {
+        let base = if self.lcdc & ctrl_flags::BGW_TILE_DATA != 0 {
+            tile as usize * 16
+        } else {
+            (0x1000 + tile as i8 as isize * 16) as usize
+        };
+        base + row as usize * 2
+    }
+
+    fn fetch_background(&mut self) {
+        if self.fetcher.step == 6 {
+            if !self.bg_fifo.is_empty() {
+                return;
+            }
+            let (low, high) = (self.fetcher.low, self.fetcher.high);
+            for bit in (0..8).rev() {
+                let color = (high >> bit & 1) << 1 | (low >> bit & 1);
+                self.bg_fifo.push(Pixel {color, flags: 0});
+            }
+            // the next fetch starts in the same dot
+            self.fetcher.column = self.fetcher.column.wrapping_add(1);
+            self.fetcher.step = 1;
+        }
+        let y = if self.fetcher.window {self.window_line} else {self.ly.wrapping_add(self.scy)};
+        match self.fetcher.step {
+            1 => {
+                let (map_flag, column) = if self.fetcher.window {
+                    (ctrl_flags::WINDOW_TMA, self.fetcher.column)
+                } else {
+                    (ctrl_flags::BG_TMA, (self.scx >> 3) + self.fetcher.column)
+                };
+                let map = if self.lcdc & map_flag == 0 {0x1800} else {0x1c00};
+                self.fetcher.tile = self.vram[map + 32 * (y as usize / 8) + (column as usize & 31)];
+            },
+            3 => {
+                self.fetcher.low = self.vram[self.tile_address(self.fetcher.tile, y % 8)];
+            },
+            5 if self.fetcher.first => {
+                self.fetcher.first = false;
+                self.fetcher.step = 0;
+                return;
+            },
+            5 => {
+                self.fetcher.high = self.vram[self.tile_address(self.fetcher.tile, y % 8) + 1];
+            },
+            _ => (),
+        }
+        self.fetcher.step += 1;
+    }
+
+    // Mixes the row of an object into the object fifo. Objects are fetched
+    // from left to right and in oam order for the same x, the ones fetched
+    // earlier win where they are not transparent.
+    fn fetch_object(&mut self, obj:usize) {
+        let y = self.oam[4*obj];
+        let x = self.oam[4*obj + 1];
+        let flags = self.oam[4*obj + 3];
+        let height = self.obj_height();
+        // 8x16 objects use an even and the following odd tile
+        let tile = if height == 16 {self.oam[4*obj + 2] & 0xfe} else {self.oam[4*obj + 2]} as usize;
+        let mut row = self.ly.wrapping_add(16).wrapping_sub(y) & (height - 1);
+        if flags & oam_flags::FLIP_Y != 0 {
+            row = height - 1 - row;
+        }
+        let address = tile * 16 + row as usize * 2;
+        let (mut low, mut high) = (self.vram[address], self.vram[address + 1]);
+        if flags & oam_flags::FLIP_X != 0 {
+            low = low.reverse_bits();
+            high = high.reverse_bits();
+        }
+
+        // the part left of the current position is not shown
+        let skip = (self.x + 8).saturating_sub(x).min(8);
+        for i in skip..8 {
+            let bit = 7 - i;
+            let pixel = Pixel {color: (high >> bit & 1) << 1 | (low >> bit & 1), flags};
+            let index = (i - skip) as usize;
+            if index >= self.obj_fifo.len {
+                self.obj_fifo.push(pixel);
+            } else if self.obj_fifo.pixels[index].color == 0 {
+                self.obj_fifo.pixels[index] = pixel;
+            }
+        }
+    }
+
+    fn shift_pixel(&mut self) {
+        let bg = match self.bg_fifo.pop() {
+            Some(pixel) => pixel,
+            None => return,
+        };
+        if self.discard > 0 {
+            self.discard -= 1;
+            return;
+        }
+        let obj = self.obj_fifo.pop();
+
+        // The palettes are applied as the pixel is shifted out, so a write
+        // in the middle of the line changes the pixels from there on. With
+        // the background off it is white whatever BGP says.
+        let bg_enable = self.lcdc & ctrl_flags::BG_ENABLE != 0;
+        let mut color = if bg_enable {bg.color} else {0};
+        let mut shade = if bg_enable {self.bgp >> (2 * color) & 0x03} else {0};
+        // colour 0 of an object is transparent, with the priority flag set
+        // the object only shows where the background has colour 0
+        if let Some(obj) = obj {
+            if obj.color != 0 && (obj.flags & oam_flags::PRIORITY == 0 || color == 0) {
+                let palette = if obj.flags & oam_flags::PALETTE1 != 0 {self.obp1} else {self.obp0};
+                color = obj.color;
+                shade = palette >> (2 * color) & 0x03;
+            }
+        }
+        self.colors[self.ly as usize * LCD_WIDTH + self.x as usize] = color;
+        self.lcd.put_pixel(self.x as u32, self.ly as u32, LCD_PALETTE[shade as usize]);
+
+        self.x += 1;
+        if self.x >= 160 {
+            self.mode = 0;
+        }
     }
 
 
@@ -188,3 +648,321 @@ impl Ppu {
 //            0xff4a => window_x
 //            0xff4b => window_y
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // BGP shows the colour numbers as they are
+    fn test_ppu() -> Ppu {
+        let mut ppu = Ppu::new();
+        ppu.write(0xff47, 0xe4);
+        ppu
+    }
+
+    // runs to mode 3 on line 0 after an oam search, returns how many dots it lasts
+    fn mode3_length(ppu:&mut Ppu) -> isize {
+        while !(ppu.mode == 2 && ppu.ly == 0) {
+            ppu.tick(1);
+        }
+        while ppu.mode != 3 {
+            ppu.tick(1);
+        }
+        let mut dots = 0;
+        while ppu.mode == 3 {
+            ppu.tick(1);
+            dots += 1;
+        }
+        dots
+    }
+
+    // runs until the end of mode 3 on the given line
+    fn draw_line(ppu:&mut Ppu, ly:u8) {
+        while !(ppu.mode == 2 && ppu.ly == ly) {
+            ppu.tick(1);
+        }
+        while ppu.mode != 3 {
+            ppu.tick(1);
+        }
+        while ppu.mode == 3 {
+            ppu.tick(1);
+        }
+    }
+
+    fn lcd_color(ppu:&Ppu, x:u32, y:u32) -> usize {
+        let pixel = ppu.lcd.get_pixel(x, y);
+        LCD_PALETTE.iter().position(|color| color == pixel).unwrap()
+    }
+
+    #[test]
+    fn test_mode3_length() {
+        let mut ppu = test_ppu();
+        ppu.write(0xff40, 0x93);
+        assert_eq!(mode3_length(&mut ppu), 172);
+
+        // the pixels scrolled out still go through the fifo
+        ppu.write(0xff43, 0x05);
+        assert_eq!(mode3_length(&mut ppu), 172 + 5);
+
+        // an object waits for the background fetch in progress, which
+        // just started when it is aligned with the tiles
+        ppu.write(0xff43, 0x00);
+        ppu.write(0xfe00, 16);
+        ppu.write(0xfe01, 8 + 16);
+        assert_eq!(mode3_length(&mut ppu), 172 + 11);
+        ppu.write(0xfe01, 8 + 16 + 5);
+        assert_eq!(mode3_length(&mut ppu), 172 + 6);
+    }
+
+    #[test]
+    fn test_background() {
+        let mut ppu = test_ppu();
+        // tile 1 has colour 1 in its left column, colour 2 in its right one
+        ppu.write(0x8010, 0x80);
+        ppu.write(0x8011, 0x01);
+        ppu.write(0x9800, 0x01);
+        ppu.write(0xff40, 0x91);
+        mode3_length(&mut ppu);
+        assert_eq!(lcd_color(&ppu, 0, 0), 1);
+        assert_eq!(lcd_color(&ppu, 7, 0), 2);
+        assert_eq!(lcd_color(&ppu, 8, 0), 0);
+
+        // scrolled by a pixel, and the signed tile numbers from 0x9000
+        ppu.write(0x9010, 0x40);
+        ppu.write(0xff43, 0x01);
+        ppu.write(0xff40, 0x81);
+        mode3_length(&mut ppu);
+        assert_eq!(lcd_color(&ppu, 0, 0), 1);
+        assert_eq!(lcd_color(&ppu, 1, 0), 0);
+    }
+
+    #[test]
+    fn test_window() {
+        let mut ppu = test_ppu();
+        // the window shows tile 1 from the map at 0x9800, the background
+        // tile 0 from the one at 0x9c00
+        ppu.write(0x8010, 0x0f);
+        ppu.write(0x9800, 0x01);
+        ppu.write(0xff40, 0xb9);
+        ppu.write(0xff4b, 7 + 80);
+        assert_eq!(mode3_length(&mut ppu), 172 + 6);
+        assert_eq!(lcd_color(&ppu, 83, 0), 0);
+        assert_eq!(lcd_color(&ppu, 84, 0), 1);
+
+        // cut off on the left
+        ppu.write(0xff4b, 3);
+        mode3_length(&mut ppu);
+        assert_eq!(lcd_color(&ppu, 3, 0), 1);
+        assert_eq!(lcd_color(&ppu, 4, 0), 0);
+    }
+
+    #[test]
+    fn test_window_line() {
+        let mut ppu = test_ppu();
+        // row 0 of tile 1 has colour 1, row 1 colour 2
+        ppu.write(0x8010, 0xff);
+        ppu.write(0x8013, 0xff);
+        ppu.write(0x9800, 0x01);
+        ppu.write(0xff4a, 2);
+        ppu.write(0xff4b, 7);
+        ppu.write(0xff40, 0xb9);
+        draw_line(&mut ppu, 2);
+        // hidden on line 3, so line 4 continues with the second row
+        ppu.write(0xff40, 0x99);
+        draw_line(&mut ppu, 3);
+        ppu.write(0xff40, 0xb9);
+        draw_line(&mut ppu, 4);
+        assert_eq!(lcd_color(&ppu, 0, 1), 0);
+        assert_eq!(lcd_color(&ppu, 0, 2), 1);
+        assert_eq!(lcd_color(&ppu, 0, 3), 0);
+        assert_eq!(lcd_color(&ppu, 0, 4), 2);
+    }
+
+    // places an object at the left edge of line 0
+    fn object(ppu:&mut Ppu, obj:u16, x:u8, tile:u8, flags:u8) {
+        ppu.write(0xfe00 + 4*obj, 16);
+        ppu.write(0xfe01 + 4*obj, x);
+        ppu.write(0xfe02 + 4*obj, tile);
+        ppu.write(0xfe03 + 4*obj, flags);
+    }
+
+    #[test]
+    fn test_objects() {
+        let mut ppu = test_ppu();
+        // tile 1: colour 1 in the left half, tile 2: colour 3 in the right half
+        ppu.write(0x8010, 0xf0);
+        ppu.write(0x8020, 0x0f);
+        ppu.write(0x8021, 0x0f);
+        // background tile 3 has colour 2 everywhere
+        ppu.write(0x8031, 0xff);
+        ppu.write(0x9801, 0x03);
+        ppu.write(0xff40, 0x93);
+        ppu.write(0xff48, 0xe4);
+        ppu.write(0xff49, 0x40);
+
+        object(&mut ppu, 0, 8, 1, 0x00);
+        // overlaps the first one, which wins where it is not transparent
+        object(&mut ppu, 1, 10, 2, oam_flags::PALETTE1);
+        // flipped and behind the background
+        object(&mut ppu, 2, 16, 2, oam_flags::FLIP_X | oam_flags::PRIORITY);
+        mode3_length(&mut ppu);
+        assert_eq!(lcd_color(&ppu, 0, 0), 1);
+        assert_eq!(lcd_color(&ppu, 3, 0), 1);
+        // the first one is transparent here, colour 3 is 1 in OBP1
+        assert_eq!(lcd_color(&ppu, 5, 0), 0);
+        assert_eq!(lcd_color(&ppu, 6, 0), 1);
+        assert_eq!(lcd_color(&ppu, 9, 0), 1);
+        // flipped: colour 3 on the left, but the background is not 0
+        assert_eq!(lcd_color(&ppu, 10, 0), 2);
+        assert_eq!(lcd_color(&ppu, 12, 0), 2);
+    }
+
+    #[test]
+    fn test_object_order() {
+        let mut ppu = test_ppu();
+        // tile 1 is colour 1 everywhere, tile 2 colour 3
+        ppu.write(0x8010, 0xff);
+        ppu.write(0x8020, 0xff);
+        ppu.write(0x8021, 0xff);
+        ppu.write(0xff40, 0x93);
+        ppu.write(0xff48, 0xe4);
+
+        // both hang off the left edge, the later one in OAM is further left
+        object(&mut ppu, 0, 5, 1, 0x00);
+        object(&mut ppu, 1, 3, 2, 0x00);
+        mode3_length(&mut ppu);
+        assert_eq!(lcd_color(&ppu, 0, 0), 3);
+        assert_eq!(lcd_color(&ppu, 2, 0), 3);
+        assert_eq!(lcd_color(&ppu, 3, 0), 1);
+        assert_eq!(lcd_color(&ppu, 4, 0), 1);
+        assert_eq!(lcd_color(&ppu, 5, 0), 0);
+    }
+
+    #[test]
+    fn test_object_size_and_limit() {
+        let mut ppu = test_ppu();
+        // line 8 of an 8x16 object is the first of the odd tile, flipped it
+        // is the last of the even one
+        ppu.write(0x8030, 0xff);
+        ppu.write(0x802f, 0xff);
+        ppu.write(0x8020, 0xff);
+        ppu.write(0xff40, 0x97);
+        ppu.write(0xff48, 0xe4);
+        object(&mut ppu, 0, 8, 2, oam_flags::FLIP_Y);
+        ppu.write(0xfe00, 8);
+        // only 10 objects per line
+        for obj in 1..12 {
+            object(&mut ppu, obj, 8 + 8 * obj as u8, 3, 0x00);
+        }
+        draw_line(&mut ppu, 0);
+        assert_eq!(lcd_color(&ppu, 0, 0), 2);
+        assert_eq!(lcd_color(&ppu, 8 * 9, 0), 1);
+        assert_eq!(lcd_color(&ppu, 8 * 10, 0), 0);
+    }
+
+    #[test]
+    fn test_palettes() {
+        let mut ppu = Ppu::new();
+        // colour 1 in the left half of tile 0, colour 3 in tile 1
+        ppu.write(0x8000, 0xf0);
+        ppu.write(0x8010, 0xff);
+        ppu.write(0x8011, 0xff);
+        ppu.write(0xfe00, 16);
+        ppu.write(0xfe01, 8 + 8);
+        ppu.write(0xfe02, 1);
+        ppu.write(0xfe03, oam_flags::PALETTE1);
+        ppu.write(0xff40, 0x93);
+        ppu.write(0xff47, 0x0c);
+        ppu.write(0xff49, 0x80);
+
+        run_until(&mut ppu, |ppu| ppu.mode == 2 && ppu.ly == 0);
+        run_until(&mut ppu, |ppu| ppu.mode == 3);
+        // run past pixel 40 and change BGP there
+        ppu.tick(12 + 11 + 40);
+        assert_eq!(ppu.x, 40);
+        ppu.write(0xff47, 0x08);
+        while ppu.mode == 3 {
+            ppu.tick(1);
+        }
+        assert_eq!(lcd_color(&ppu, 0, 0), 3);
+        assert_eq!(lcd_color(&ppu, 4, 0), 0);
+        assert_eq!(lcd_color(&ppu, 8, 0), 2);
+        assert_eq!(lcd_color(&ppu, 32, 0), 3);
+        assert_eq!(lcd_color(&ppu, 40, 0), 2);
+        assert_eq!(ppu.colors[0..9], [1, 1, 1, 1, 0, 0, 0, 0, 3]);
+        assert_eq!(ppu.colors[40], 1);
+    }
+
+    // runs until the condition holds, returns the interrupts raised on the way
+    fn run_until(ppu:&mut Ppu, done:impl Fn(&Ppu) -> bool) -> u8 {
+        let mut interrupts = 0;
+        while !done(ppu) {
+            interrupts |= ppu.tick(1);
+        }
+        interrupts
+    }
+
+    #[test]
+    fn test_stat() {
+        let mut ppu = test_ppu();
+        ppu.write(0xff40, 0x91);
+        ppu.write(0xff45, 2);
+        ppu.write(0xff41, stat_flags::LY_INTERRUPT | 0x07);
+        run_until(&mut ppu, |ppu| ppu.ly == 1 && ppu.mode == 2);
+        assert_eq!(ppu.read(0xff41), 0xc2);
+        assert_eq!(run_until(&mut ppu, |ppu| ppu.ly == 2), 0x02);
+        assert_eq!(ppu.read(0xff41), 0xc6);
+        assert_eq!(run_until(&mut ppu, |ppu| ppu.mode == 3), 0x00);
+        assert_eq!(ppu.read(0xff41), 0xc7);
+        assert_eq!(run_until(&mut ppu, |ppu| ppu.ly == 3 && ppu.mode == 0), 0x00);
+        assert_eq!(ppu.read(0xff41), 0xc0);
+    }
+
+    #[test]
+    fn test_stat_blocking() {
+        let mut ppu = test_ppu();
+        ppu.write(0xff40, 0x91);
+        ppu.write(0xff41, stat_flags::M0_INTERRUPT | stat_flags::M1_INTERRUPT);
+        run_until(&mut ppu, |ppu| ppu.ly == 143 && ppu.mode == 2);
+        assert_eq!(run_until(&mut ppu, |ppu| ppu.mode == 0), 0x02);
+        // hblank goes straight into vblank, the line stays high
+        assert_eq!(run_until(&mut ppu, |ppu| ppu.mode == 1), 0x01);
+
+        ppu.write(0xff41, stat_flags::M1_INTERRUPT);
+        run_until(&mut ppu, |ppu| ppu.ly == 143 && ppu.mode == 0);
+        assert_eq!(run_until(&mut ppu, |ppu| ppu.mode == 1), 0x03);
+    }
+
+    #[test]
+    fn test_lcd_off() {
+        let mut ppu = test_ppu();
+        ppu.write(0x8000, 0xff);
+        ppu.write(0xff40, 0x91);
+        draw_line(&mut ppu, 8);
+        assert_eq!(lcd_color(&ppu, 0, 8), 1);
+
+        ppu.write(0xff40, 0x11);
+        assert_eq!(ppu.read(0xff44), 0);
+        assert_eq!(ppu.read(0xff41) & 0x03, 0);
+        assert_eq!(lcd_color(&ppu, 0, 8), 0);
+        // frames go on without vblank
+        let frames = ppu.frames;
+        assert_eq!(ppu.tick(CYCLES_PER_FRAME), 0);
+        assert_eq!(ppu.frames, frames + 1);
+        assert_eq!(ppu.read(0xff44), 0);
+
+        // the first line skips the oam search and is 4 dots short, a window
+        // at WY 0 still shows on it; tile 1 is colour 3 in the window map
+        ppu.write(0x8010, 0xff);
+        ppu.write(0x8011, 0xff);
+        ppu.write(0x9c00, 0x01);
+        ppu.write(0xff4a, 0);
+        ppu.write(0xff4b, 7);
+        ppu.write(0xff40, 0xf1);
+        assert_eq!(run_until(&mut ppu, |ppu| ppu.mode == 3), 0);
+        assert_eq!(ppu.dot, 80);
+        run_until(&mut ppu, |ppu| ppu.mode == 2);
+        assert_eq!(ppu.ly, 1);
+        assert_eq!(lcd_color(&ppu, 0, 0), 3);
+    }
+}
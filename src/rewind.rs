@@ -0,0 +1,183 @@
+use std::collections::VecDeque;
+
+// Ring buffer of save states taken every few frames.
+//
+// Only the newest snapshot is kept as is. Each older one is stored as the
+// xor with its successor, run length encoded, which is small as most of the
+// memory does not change between two snapshots. Stepping back undoes one
+// delta at a time, running out of budget drops the oldest ones.
+pub struct Rewind {
+    interval: usize,
+    budget: usize,
+    frames: usize,
+    latest: Vec<u8>,
+    deltas: VecDeque<Vec<u8>>,
+    size: usize,
+}
+
+fn write_varint(out:&mut Vec<u8>, mut value:usize) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data:&[u8], pos:&mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    while *pos < data.len() {
+        let byte = data[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    value
+}
+
+// alternating runs of unchanged bytes and literal xor bytes
+fn encode_delta(old:&[u8], new:&[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < old.len() {
+        let start = pos;
+        while pos < old.len() && old[pos] == new[pos] {
+            pos += 1;
+        }
+        write_varint(&mut out, pos - start);
+        let start = pos;
+        while pos < old.len() && old[pos] != new[pos] {
+            pos += 1;
+        }
+        write_varint(&mut out, pos - start);
+        out.extend(old[start..pos].iter().zip(&new[start..pos]).map(|(o, n)| o ^ n));
+    }
+    out
+}
+
+fn apply_delta(state:&mut [u8], delta:&[u8]) {
+    let mut pos = 0;
+    let mut index = 0;
+    while index < delta.len() {
+        pos += read_varint(delta, &mut index);
+        let len = read_varint(delta, &mut index);
+        for (byte, x) in state[pos..pos+len].iter_mut().zip(&delta[index..index+len]) {
+            *byte ^= x;
+        }
+        pos += len;
+        index += len;
+    }
+}
+
+impl Rewind {
+    pub fn new(interval:usize, budget:usize) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            frames: 0,
+            latest: Vec::new(),
+            deltas: VecDeque::new(),
+            size: 0,
+        }
+    }
+
+    // counts a finished frame, true when a snapshot is due
+    pub fn frame(&mut self) -> bool {
+        self.frames += 1;
+        if self.frames >= self.interval {
+            self.frames = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn push(&mut self, snapshot:Vec<u8>) {
+        if self.latest.len() == snapshot.len() {
+            let delta = encode_delta(&self.latest, &snapshot);
+            self.size += delta.len();
+            self.deltas.push_back(delta);
+        } else {
+            // a snapshot of a different size can not be diffed, start over
+            self.deltas.clear();
+            self.size = self.latest.len();
+        }
+        self.size += snapshot.len();
+        self.size -= self.latest.len();
+        self.latest = snapshot;
+
+        while self.size > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => {self.size -= delta.len();},
+                None => break,
+            }
+        }
+    }
+
+    // takes the newest snapshot out of the buffer
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        if self.latest.is_empty() {
+            return None;
+        }
+        let mut previous = self.latest.clone();
+        match self.deltas.pop_back() {
+            Some(delta) => {
+                apply_delta(&mut previous, &delta);
+                self.size -= delta.len();
+            },
+            None => {
+                self.size = 0;
+                previous.clear();
+            },
+        }
+        self.frames = 0;
+        Some(std::mem::replace(&mut self.latest, previous))
+    }
+
+    pub fn count(&self) -> usize {
+        if self.latest.is_empty() {0} else {self.deltas.len() + 1}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewind() {
+        let mut rewind = Rewind::new(2, 1 << 20);
+        assert!(!rewind.frame());
+        assert!(rewind.frame());
+
+        let mut snapshot = vec![0u8; 1000];
+        for i in 0..5 {
+            snapshot[10 * i] = i as u8 + 1;
+            snapshot[999] = i as u8;
+            rewind.push(snapshot.clone());
+        }
+        assert_eq!(rewind.count(), 5);
+        for i in (0..5).rev() {
+            let state = rewind.pop().unwrap();
+            assert_eq!(state[10 * i], i as u8 + 1);
+            assert_eq!(state[999], i as u8);
+            assert_eq!(state[10 * (i + 1)], 0);
+        }
+        assert_eq!(rewind.pop(), None);
+    }
+
+    #[test]
+    fn test_rewind_budget() {
+        let mut rewind = Rewind::new(1, 1100);
+        let mut snapshot = vec![0u8; 1000];
+        for i in 0..100 {
+            snapshot[i] = 0xff;
+            rewind.push(snapshot.clone());
+        }
+        assert!(rewind.count() > 1);
+        assert!(rewind.count() < 100);
+        assert!(rewind.size <= 1100);
+    }
+}
@@ -0,0 +1,322 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::cpu::Cpu;
+
+// A save state is the magic and a version number followed by sections,
+// each a four byte tag, the payload length as u32 and the payload.
+// Readers skip sections they do not know, so new sections can be added
+// without bumping the version. The version only changes when the layout
+// of an existing section does.
+const MAGIC:&[u8;8] = b"RBOYSTAT";
+const VERSION:u16 = 9;
+
+const CPU:&[u8;4] = b"CPU ";
+const MMU:&[u8;4] = b"MMU ";
+const TIMER:&[u8;4] = b"TIMR";
+const PPU:&[u8;4] = b"PPU ";
+const SERIAL:&[u8;4] = b"SERL";
+const CARTRIDGE:&[u8;4] = b"CART";
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    SizeMismatch {expected:usize, actual:usize},
+    MissingSection([u8;4]),
+    WrongCartridge,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        use StateError::*;
+        match self {
+            Io(err) => write!(f, "{}", err),
+            BadMagic => write!(f, "not a save state"),
+            UnsupportedVersion(version) => write!(f, "unsupported save state version {}", version),
+            Truncated => write!(f, "save state is truncated"),
+            SizeMismatch {expected, actual} => write!(f, "save state holds a block of {} bytes, expected {}", actual, expected),
+            MissingSection(tag) => write!(f, "save state has no {} section", String::from_utf8_lossy(tag).trim_end()),
+            WrongCartridge => write!(f, "save state was made with a different cartridge"),
+        }
+    }
+}
+
+impl error::Error for StateError {}
+
+impl From<io::Error> for StateError {
+    fn from(err:io::Error) -> StateError {
+        StateError::Io(err)
+    }
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn u8(&mut self, value:u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value:bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value:u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value:u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value:u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i64(&mut self, value:i64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // length prefixed
+    pub fn bytes(&mut self, value:&[u8]) {
+        self.u32(value.len() as u32);
+        self.data.extend_from_slice(value);
+    }
+
+    fn section(&mut self, tag:&[u8;4], payload:StateWriter) {
+        self.data.extend_from_slice(tag);
+        self.bytes(&payload.data);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data:&'a [u8]) -> StateReader<'a> {
+        StateReader {data}
+    }
+
+    fn take(&mut self, len:usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < len {
+            return Err(StateError::Truncated);
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        let b = self.take(8)?;
+        Ok(u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+    }
+
+    pub fn i64(&mut self) -> Result<i64, StateError> {
+        Ok(self.u64()? as i64)
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    // reads a length prefixed block into a buffer of exactly that size
+    pub fn bytes_into(&mut self, buffer:&mut [u8]) -> Result<(), StateError> {
+        let bytes = self.bytes()?;
+        if bytes.len() != buffer.len() {
+            return Err(StateError::SizeMismatch {expected: buffer.len(), actual: bytes.len()});
+        }
+        buffer.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+pub fn save_state(cpu:&Cpu) -> Vec<u8> {
+    let mut state = StateWriter::default();
+    state.data.extend_from_slice(MAGIC);
+    state.u16(VERSION);
+
+    let mut section = StateWriter::default();
+    cpu.save_state(&mut section);
+    state.section(CPU, section);
+
+    let mut section = StateWriter::default();
+    cpu.bus.save_state(&mut section);
+    state.section(MMU, section);
+
+    let mut section = StateWriter::default();
+    cpu.bus.timer().save_state(&mut section);
+    state.section(TIMER, section);
+
+    let mut section = StateWriter::default();
+    cpu.bus.serial().save_state(&mut section);
+    state.section(SERIAL, section);
+
+    let mut section = StateWriter::default();
+    cpu.bus.ppu().save_state(&mut section);
+    state.section(PPU, section);
+
+    let mut section = StateWriter::default();
+    cpu.bus.cartridge().save_state(&mut section);
+    state.section(CARTRIDGE, section);
+
+    state.data
+}
+
+pub fn load_state(cpu:&mut Cpu, data:&[u8]) -> Result<(), StateError> {
+    let mut reader = StateReader::new(data);
+    if reader.take(MAGIC.len()).map_err(|_| StateError::BadMagic)? != MAGIC {
+        return Err(StateError::BadMagic);
+    }
+    let version = reader.u16()?;
+    if version != VERSION {
+        return Err(StateError::UnsupportedVersion(version));
+    }
+
+    let sections = parse_sections(reader.data)?;
+    for tag in &[CPU, MMU, TIMER, PPU, CARTRIDGE] {
+        if !sections.iter().any(|(t, _)| t == *tag) {
+            return Err(StateError::MissingSection(**tag));
+        }
+    }
+
+    // a section can still turn out to be broken halfway through, the
+    // machine goes back to where it was then instead of being left half
+    // restored
+    let backup = save_state(cpu);
+    load_sections(cpu, &sections).inspect_err(|_| {
+        let sections = parse_sections(&backup[MAGIC.len() + 2..]).expect("a state saved just now parses");
+        load_sections(cpu, &sections).expect("a state saved just now loads");
+    })
+}
+
+fn load_sections(cpu:&mut Cpu, sections:&[Section]) -> Result<(), StateError> {
+    let section = |tag:&[u8;4]| -> Result<StateReader, StateError> {
+        sections.iter()
+            .find(|(t, _)| t == tag)
+            .map(|(_, payload)| StateReader::new(payload))
+            .ok_or(StateError::MissingSection(*tag))
+    };
+
+    // the cartridge goes first, it is the section that can refuse the state
+    cpu.bus.cartridge_mut().load_state(&mut section(CARTRIDGE)?)?;
+    cpu.load_state(&mut section(CPU)?)?;
+    cpu.bus.load_state(&mut section(MMU)?)?;
+    cpu.bus.timer_mut().load_state(&mut section(TIMER)?)?;
+    cpu.bus.ppu_mut().load_state(&mut section(PPU)?)?;
+    // states from before the serial port have no section for it
+    if let Ok(mut serial) = section(SERIAL) {
+        cpu.bus.serial_mut().load_state(&mut serial)?;
+    }
+    Ok(())
+}
+
+// tag and payload
+type Section<'a> = (&'a [u8], &'a [u8]);
+
+// splits what follows the version into sections
+fn parse_sections(data:&[u8]) -> Result<Vec<Section<'_>>, StateError> {
+    let mut reader = StateReader::new(data);
+    let mut sections = Vec::new();
+    while !reader.data.is_empty() {
+        let tag = reader.take(4)?;
+        let payload = reader.bytes()?;
+        sections.push((tag, payload));
+    }
+    Ok(sections)
+}
+
+// game.gb -> game.ss1
+pub fn slot_path(rom:&Path, slot:u8) -> PathBuf {
+    rom.with_extension(format!("ss{}", slot))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Mmu;
+
+    #[test]
+    fn test_round_trip() {
+        let mut cpu = Cpu::new(Mmu::new());
+        cpu.a = 0x12;
+        cpu.sp = 0xfffe;
+        cpu.pc = 0x0150;
+        cpu.bus.write(0xc000, 0x34);
+        cpu.bus.write(0x8010, 0x56);
+        cpu.bus.ppu_mut().mode = 3;
+        let mut data = save_state(&cpu);
+
+        // sections unknown to this version are skipped
+        data.extend_from_slice(b"NEW ");
+        data.extend_from_slice(&2u32.to_le_bytes());
+        data.extend_from_slice(&[1, 2]);
+
+        let mut loaded_cpu = Cpu::new(Mmu::new());
+        load_state(&mut loaded_cpu, &data).unwrap();
+        assert_eq!(loaded_cpu.a, 0x12);
+        assert_eq!(loaded_cpu.sp, 0xfffe);
+        assert_eq!(loaded_cpu.pc, 0x0150);
+        assert_eq!(loaded_cpu.bus.read(0xc000), 0x34);
+        assert_eq!(loaded_cpu.bus.peek(0x8010), 0x56);
+        assert_eq!(loaded_cpu.bus.ppu().mode, 3);
+
+        assert!(matches!(load_state(&mut loaded_cpu, &data[..100]), Err(StateError::Truncated)));
+        assert!(matches!(load_state(&mut loaded_cpu, b"RBOYSAVE"), Err(StateError::BadMagic)));
+    }
+
+    #[test]
+    fn test_failed_load() {
+        let mut cpu = Cpu::new(Mmu::new());
+        cpu.a = 0x12;
+        cpu.bus.write(0xc000, 0x34);
+        let data = save_state(&cpu);
+
+        // the ppu section is cut short, everything before it loads fine
+        let mut state = StateWriter::default();
+        state.data.extend_from_slice(&data[..MAGIC.len() + 2]);
+        for (tag, payload) in parse_sections(&data[MAGIC.len() + 2..]).unwrap() {
+            state.data.extend_from_slice(tag);
+            state.bytes(if tag == PPU {&payload[..payload.len() / 2]} else {payload});
+        }
+
+        let mut loaded_cpu = Cpu::new(Mmu::new());
+        loaded_cpu.a = 0x56;
+        loaded_cpu.bus.write(0xc000, 0x78);
+        assert!(matches!(load_state(&mut loaded_cpu, &state.data), Err(StateError::Truncated)));
+        assert_eq!(loaded_cpu.a, 0x56);
+        assert_eq!(loaded_cpu.bus.read(0xc000), 0x78);
+
+        let mut buffer = [0; 4];
+        let mut state = StateWriter::default();
+        state.bytes(&[1, 2, 3]);
+        let result = StateReader::new(&state.data).bytes_into(&mut buffer);
+        assert!(matches!(result, Err(StateError::SizeMismatch {expected: 4, actual: 3})));
+    }
+}
@@ -0,0 +1,62 @@
+// Runs Blargg's test roms (https://github.com/retrio/gb-test-roms) and checks
+// the result they print over the serial port. They are ignored by default, run
+// them with `cargo test --test blargg -- --ignored` and BLARGG_ROMS pointing
+// at a checkout of the test roms.
+use std::env;
+use std::path::PathBuf;
+
+use rustyboy::GameBoy;
+
+const BOOT_ROM:&str = concat!(env!("CARGO_MANIFEST_DIR"), "/RBOY_ROM.bin");
+
+// one minute, the individual roms finish in a few seconds
+const MAX_FRAMES:usize = 3600;
+
+fn run_blargg(path:&str) {
+    let dir = env::var_os("BLARGG_ROMS").expect("BLARGG_ROMS is not set");
+    let rom = PathBuf::from(dir).join(path);
+    assert!(rom.exists(), "{} not found", rom.display());
+
+    let (mut gb, _header) = GameBoy::load(rom.to_str().unwrap(), BOOT_ROM).unwrap();
+    for _ in 0..MAX_FRAMES {
+        gb.run_frame();
+        let output = String::from_utf8_lossy(gb.serial_output());
+        if output.contains("Passed") {
+            return;
+        }
+        if output.contains("Failed") {
+            panic!("{}:\n{}", path, output);
+        }
+    }
+    panic!("{} did not finish:\n{}", path, String::from_utf8_lossy(gb.serial_output()));
+}
+
+macro_rules! blargg_tests {
+    ($($name:ident: $path:expr,)*) => {
+        $(
+            #[test]
+            #[ignore = "needs BLARGG_ROMS"]
+            fn $name() {
+                run_blargg($path);
+            }
+        )*
+    }
+}
+
+blargg_tests! {
+    cpu_instrs_01_special: "cpu_instrs/individual/01-special.gb",
+    cpu_instrs_02_interrupts: "cpu_instrs/individual/02-interrupts.gb",
+    cpu_instrs_03_op_sp_hl: "cpu_instrs/individual/03-op sp,hl.gb",
+    cpu_instrs_04_op_r_imm: "cpu_instrs/individual/04-op r,imm.gb",
+    cpu_instrs_05_op_rp: "cpu_instrs/individual/05-op rp.gb",
+    cpu_instrs_06_ld_r_r: "cpu_instrs/individual/06-ld r,r.gb",
+    cpu_instrs_07_jr_jp_call_ret_rst: "cpu_instrs/individual/07-jr,jp,call,ret,rst.gb",
+    cpu_instrs_08_misc_instrs: "cpu_instrs/individual/08-misc instrs.gb",
+    cpu_instrs_09_op_r_r: "cpu_instrs/individual/09-op r,r.gb",
+    cpu_instrs_10_bit_ops: "cpu_instrs/individual/10-bit ops.gb",
+    cpu_instrs_11_op_a_hl: "cpu_instrs/individual/11-op a,(hl).gb",
+    instr_timing: "instr_timing/instr_timing.gb",
+    mem_timing_01_read_timing: "mem_timing/individual/01-read_timing.gb",
+    mem_timing_02_write_timing: "mem_timing/individual/02-write_timing.gb",
+    mem_timing_03_modify_timing: "mem_timing/individual/03-modify_timing.gb",
+}
@@ -0,0 +1,101 @@
+// Runs the mooneye-gb acceptance tests (https://github.com/Gekkio/mooneye-test-suite)
+// found below MOONEYE_ROMS and prints a table of the results. A test ends with
+// the software breakpoint LD B,B and leaves the fibonacci numbers in the
+// registers when it passed. The test is ignored by default, run it with
+// `cargo test --test mooneye -- --ignored` and MOONEYE_ROMS set.
+//
+// Known failures do not fail the test, the table is there to track them.
+// Set MOONEYE_STRICT to require every rom to pass.
+use std::env;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use rustyboy::GameBoy;
+use rustyboy::cpu::CpuEvent;
+
+const BOOT_ROM:&str = concat!(env!("CARGO_MANIFEST_DIR"), "/RBOY_ROM.bin");
+
+// the tests themselves take well below a second, the boot rom about six
+const MAX_FRAMES:u64 = 1200;
+
+#[derive(PartialEq)]
+enum Outcome {
+    Pass,
+    Fail,
+    Timeout,
+    Crash,
+}
+
+fn find_roms(dir:&Path, roms:&mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            find_roms(&path, roms);
+        } else if path.extension().is_some_and(|ext| ext == "gb") {
+            roms.push(path);
+        }
+    }
+}
+
+// names end in the models they are meant for, e.g. boot_regs-dmgABC or
+// di_timing-GS, only the ones for a DMG are run
+fn for_dmg(rom:&Path) -> bool {
+    let name = rom.file_stem().unwrap().to_string_lossy();
+    match name.rsplit_once('-') {
+        Some((_, models)) => models.contains("dmgABC") || models.contains('G'),
+        None => true,
+    }
+}
+
+fn run_mooneye(rom:&Path) -> Outcome {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let (mut gb, _header) = GameBoy::load(rom.to_str().unwrap(), BOOT_ROM).unwrap();
+        match gb.run_until_event(MAX_FRAMES) {
+            Some(CpuEvent::SoftwareBreakpoint) if gb.mooneye_passed() => Outcome::Pass,
+            Some(_) => Outcome::Fail,
+            None => Outcome::Timeout,
+        }
+    }));
+    result.unwrap_or(Outcome::Crash)
+}
+
+#[test]
+#[ignore = "needs MOONEYE_ROMS"]
+fn mooneye_acceptance() {
+    let dir = PathBuf::from(env::var_os("MOONEYE_ROMS").expect("MOONEYE_ROMS is not set"));
+    let mut roms = Vec::new();
+    find_roms(&dir, &mut roms);
+    roms.retain(|rom| for_dmg(rom));
+    roms.sort();
+    assert!(!roms.is_empty(), "no roms found below {}", dir.display());
+
+    // the emulator panics on some roms, keep the table readable
+    panic::set_hook(Box::new(|_| ()));
+    let results:Vec<(PathBuf, Outcome)> = roms.into_iter().map(|rom| {
+        let outcome = run_mooneye(&rom);
+        (rom, outcome)
+    }).collect();
+    let _ = panic::take_hook();
+
+    let passed = results.iter().filter(|(_, outcome)| *outcome == Outcome::Pass).count();
+    println!("{:<60} result", "rom");
+    for (rom, outcome) in &results {
+        let outcome = match outcome {
+            Outcome::Pass => "pass",
+            Outcome::Fail => "FAIL",
+            Outcome::Timeout => "TIMEOUT",
+            Outcome::Crash => "CRASH",
+        };
+        println!("{:<60} {}", rom.strip_prefix(&dir).unwrap_or(rom).display(), outcome);
+    }
+    println!("{} of {} passed", passed, results.len());
+
+    if env::var_os("MOONEYE_STRICT").is_some() {
+        assert_eq!(passed, results.len());
+    }
+}
@@ -0,0 +1,178 @@
+// Runs the single instruction test vectors of https://github.com/SingleStepTests/sm83
+// against the cpu on a flat 64k bus that logs every access. Each opcode has a json file with a
+// thousand tests of an initial state, the state after one instruction and
+// the bus cycles in between. Only the sample below runs by default, the full
+// set with `cargo test --test sm83 -- --ignored` and SM83_TESTS pointing at
+// the v1 directory. SM83_FILTER=cb only runs the files whose name contains cb.
+use std::env;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+
+use serde_json::Value;
+
+use rustyboy::bus::{Access, FlatBus, LoggingBus};
+use rustyboy::cpu::Cpu;
+
+const SAMPLE:&str = r#"[{
+    "name": "3c 0000",
+    "initial": {"pc": 49152, "sp": 65534, "a": 15, "b": 0, "c": 0, "d": 0, "e": 0, "f": 16, "h": 0, "l": 0, "ime": 0, "ie": 0,
+                "ram": [[49152, 60]]},
+    "final": {"pc": 49153, "sp": 65534, "a": 16, "b": 0, "c": 0, "d": 0, "e": 0, "f": 48, "h": 0, "l": 0, "ime": 0, "ie": 0,
+              "ram": [[49152, 60]]},
+    "cycles": [[49152, 60, "r-m"]]
+}, {
+    "name": "34 0000",
+    "initial": {"pc": 256, "sp": 65534, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 192, "l": 0, "ime": 0, "ie": 0,
+                "ram": [[256, 52], [49152, 255]]},
+    "final": {"pc": 257, "sp": 65534, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 160, "h": 192, "l": 0, "ime": 0, "ie": 0,
+              "ram": [[256, 52], [49152, 0]]},
+    "cycles": [[256, 52, "r-m"], [49152, 255, "r-m"], [49152, 0, "-wm"]]
+}, {
+    "name": "76 0000",
+    "initial": {"pc": 49152, "sp": 65534, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0, "ie": 0,
+                "ram": [[49152, 118]]},
+    "final": {"pc": 49153, "sp": 65534, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0, "ie": 0,
+              "ram": [[49152, 118]]},
+    "cycles": [[49152, 118, "r-m"]]
+}]"#;
+
+fn number(value:&Value, key:&str) -> u16 {
+    value[key].as_u64().unwrap_or_else(|| panic!("{} is missing", key)) as u16
+}
+
+fn ram(state:&Value) -> Vec<(u16, u8)> {
+    state["ram"].as_array().unwrap().iter()
+        .map(|entry| (entry[0].as_u64().unwrap() as u16, entry[1].as_u64().unwrap() as u8))
+        .collect()
+}
+
+fn setup(state:&Value) -> Cpu<LoggingBus<FlatBus>> {
+    let mut cpu = Cpu::new(LoggingBus::new(FlatBus::new()));
+    cpu.a = number(state, "a") as u8;
+    cpu.f = number(state, "f") as u8;
+    cpu.b = number(state, "b") as u8;
+    cpu.c = number(state, "c") as u8;
+    cpu.d = number(state, "d") as u8;
+    cpu.e = number(state, "e") as u8;
+    cpu.h = number(state, "h") as u8;
+    cpu.l = number(state, "l") as u8;
+    cpu.sp = number(state, "sp");
+    cpu.pc = number(state, "pc");
+    cpu.ime = number(state, "ime") != 0;
+    cpu.bus.inner.memory[0xffff] = number(state, "ie") as u8;
+    for (address, value) in ram(state) {
+        cpu.bus.inner.memory[address as usize] = value;
+    }
+    cpu
+}
+
+// the differences to the expected state, empty if there are none
+fn compare(cpu:&Cpu<LoggingBus<FlatBus>>, state:&Value) -> Vec<String> {
+    let mut errors = Vec::new();
+    let registers = [
+        ("a", cpu.a as u16), ("f", cpu.f as u16), ("b", cpu.b as u16), ("c", cpu.c as u16),
+        ("d", cpu.d as u16), ("e", cpu.e as u16), ("h", cpu.h as u16), ("l", cpu.l as u16),
+        ("sp", cpu.sp), ("pc", cpu.pc), ("ime", cpu.ime as u16), ("ie", cpu.bus.inner.memory[0xffff] as u16),
+    ];
+    for (name, actual) in registers.iter() {
+        let expected = number(state, name);
+        if *actual != expected {
+            errors.push(format!("{} is {:02x}, expected {:02x}", name, actual, expected));
+        }
+    }
+    for (address, expected) in ram(state) {
+        let actual = cpu.bus.inner.memory[address as usize];
+        if actual != expected {
+            errors.push(format!("({:04x}) is {:02x}, expected {:02x}", address, actual, expected));
+        }
+    }
+    errors
+}
+
+// reads and writes of the bus cycles, internal cycles have no access
+fn expected_accesses(cycles:&Value) -> Vec<Access> {
+    cycles.as_array().unwrap().iter().filter_map(|cycle| {
+        let address = cycle[0].as_u64()? as u16;
+        let value = cycle[1].as_u64()? as u8;
+        let pins = cycle[2].as_str()?;
+        if pins.contains('r') {
+            Some(Access::Read(address, value))
+        } else if pins.contains('w') {
+            Some(Access::Write(address, value))
+        } else {
+            None
+        }
+    }).collect()
+}
+
+fn run_test(test:&Value) -> Vec<String> {
+    let mut cpu = setup(&test["initial"]);
+    let cycles = cpu.step();
+    let mut errors = compare(&cpu, &test["final"]);
+
+    let accesses = cpu.bus.take_accesses();
+    let expected = expected_accesses(&test["cycles"]);
+    if accesses != expected {
+        errors.push(format!("bus cycles are {:x?}, expected {:x?}", accesses, expected));
+    }
+    let expected_cycles = 4 * test["cycles"].as_array().unwrap().len() as isize;
+    if cycles != expected_cycles {
+        errors.push(format!("took {} cycles, expected {}", cycles, expected_cycles));
+    }
+    errors
+}
+
+// runs all tests of one file, returns the failures as name and differences
+fn run_tests(json:&str) -> Vec<(String, Vec<String>)> {
+    let tests:Value = serde_json::from_str(json).unwrap();
+    let mut failures = Vec::new();
+    for test in tests.as_array().unwrap() {
+        let name = test["name"].as_str().unwrap_or("?").to_string();
+        match panic::catch_unwind(AssertUnwindSafe(|| run_test(test))) {
+            Ok(errors) if errors.is_empty() => (),
+            Ok(errors) => failures.push((name, errors)),
+            Err(_) => failures.push((name, vec![String::from("panicked")])),
+        }
+    }
+    failures
+}
+
+#[test]
+fn sm83_sample() {
+    assert!(run_tests(SAMPLE).is_empty(), "{:?}", run_tests(SAMPLE));
+}
+
+#[test]
+#[ignore = "needs SM83_TESTS"]
+fn sm83_vectors() {
+    let dir = PathBuf::from(env::var_os("SM83_TESTS").expect("SM83_TESTS is not set"));
+    let filter = env::var("SM83_FILTER").unwrap_or_default();
+    let mut files:Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap_or_else(|err| panic!("{}: {}", dir.display(), err))
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .filter(|path| path.file_name().unwrap().to_string_lossy().contains(&filter))
+        .collect();
+    files.sort();
+    assert!(!files.is_empty(), "no test vectors found in {}", dir.display());
+
+    // a panic is reported as a failed test, keep the output readable
+    panic::set_hook(Box::new(|_| ()));
+    let mut failed_files = 0;
+    for path in &files {
+        let failures = run_tests(&fs::read_to_string(path).unwrap());
+        if let Some((name, errors)) = failures.first() {
+            failed_files += 1;
+            println!("{}: {} failed, first {}:", path.file_name().unwrap().to_string_lossy(), failures.len(), name);
+            for error in errors {
+                println!("    {}", error);
+            }
+        }
+    }
+    let _ = panic::take_hook();
+
+    println!("{} of {} opcodes passed", files.len() - failed_files, files.len());
+    assert_eq!(failed_files, 0);
+}